@@ -1,9 +1,22 @@
+use aws_sdk_sts::config::http::HttpResponse;
+use aws_sdk_sts::config::SharedHttpClient;
+use aws_sdk_sts::config::retry::RetryConfig;
+use aws_sdk_sts::config::timeout::TimeoutConfig;
 use aws_sdk_sts::config::Region;
+use aws_sdk_sts::error::{ProvideErrorMetadata, SdkError};
 
 use crate::{DEFAULT_CREDS_VERSION, RoleInfo};
+use crate::models::NetworkSettings;
+use crate::defaults::DEFAULT_ROLE_SESSION_NAME;
 use super::models::{TemporaryAwsCredentials, TemporaryAwsCredentialsBuilder, TemporaryAwsCredentialsBuilderError};
+use super::retry::with_retries;
 
+use aws_smithy_http_client::proxy::ProxyConfig;
+use aws_smithy_http_client::tls::{self, rustls_provider::CryptoMode, TlsContext, TrustStore};
+use aws_smithy_http_client::{Builder as HttpClientBuilder, ConnectorBuilder};
 use aws_sdk_sts::operation::assume_role_with_saml::AssumeRoleWithSAMLError;
+use aws_sdk_sts::operation::assume_role_with_web_identity::AssumeRoleWithWebIdentityError;
+use aws_sdk_sts::types::Credentials;
 use aws_smithy_types_convert::date_time::DateTimeExt;
 use aws_smithy_types_convert::date_time::Error as AWSDateTimeError;
 
@@ -12,32 +25,146 @@ pub type Result<T> = std::result::Result<T, AwsCredentialsError>;
 #[derive(Debug, thiserror::Error)]
 pub enum AwsCredentialsError {
     #[error(transparent)]
-    AssumeRoleFailed(#[from] aws_sdk_sts::error::SdkError<AssumeRoleWithSAMLError>),
+    AssumeRoleFailed(#[from] Box<aws_sdk_sts::error::SdkError<AssumeRoleWithSAMLError>>),
+
+    #[error(transparent)]
+    AssumeRoleWithWebIdentityFailed(#[from] Box<aws_sdk_sts::error::SdkError<AssumeRoleWithWebIdentityError>>),
+
+    #[error("STS returned no credentials")]
+    MissingCredentials,
 
     #[error(transparent)]
     BuildingCredentialsFailed(#[from] TemporaryAwsCredentialsBuilderError),
 
     #[error(transparent)]
     DateTimeError(#[from] AWSDateTimeError),
+
+    #[error("Invalid network settings: {0}")]
+    InvalidNetworkSettings(String),
 }
 
-pub async fn acquire_aws_credentials(role_info: &RoleInfo, saml_token: &str) -> Result<TemporaryAwsCredentials> {
-    let config = aws_config::defaults(aws_config::BehaviorVersion::v2024_03_28())
+// Error codes for throttling and transient failures on the identity provider side
+const RETRYABLE_ERROR_CODES: [&str; 5] = [
+    "Throttling",
+    "ThrottlingException",
+    "RequestLimitExceeded",
+    "IDPCommunicationError",
+    "ServiceUnavailable",
+];
+
+// Error codes for SAML assertions and web identity tokens that STS rejects, so that a new one
+// must be acquired
+const REJECTED_TOKEN_ERROR_CODES: [&str; 2] = ["InvalidIdentityToken", "ExpiredTokenException"];
+
+impl AwsCredentialsError {
+    /// Whether STS rejected the SAML assertion or web identity token.
+    pub fn is_rejected_token(&self) -> bool {
+        let code = match self {
+            AwsCredentialsError::AssumeRoleFailed(error) => error.code(),
+            AwsCredentialsError::AssumeRoleWithWebIdentityFailed(error) => error.code(),
+            _ => None,
+        };
+        code.is_some_and(|code| REJECTED_TOKEN_ERROR_CODES.contains(&code))
+    }
+}
+
+/// An HTTP client with the explicit proxy and CA bundle from the network settings, built like the
+/// SDK's default client. Returns `None` if neither is set, so that the default client, which uses
+/// the `HTTPS_PROXY` and `NO_PROXY` environment variables, can be used.
+fn http_client(network: &NetworkSettings) -> Result<Option<SharedHttpClient>> {
+    if !network.has_custom_transport() {
+        return Ok(None);
+    }
+    let proxy_config = match &network.proxy {
+        Some(proxy) => {
+            let proxy_config = ProxyConfig::all(proxy.as_str())
+                .map_err(|err| AwsCredentialsError::InvalidNetworkSettings(format!("Invalid proxy '{proxy}': {err}")))?;
+            match std::env::var("NO_PROXY").or_else(|_| std::env::var("no_proxy")) {
+                Ok(no_proxy) => proxy_config.no_proxy(no_proxy),
+                Err(_) => proxy_config,
+            }
+        }
+        None => ProxyConfig::from_env(),
+    };
+    let mut trust_store = TrustStore::default();
+    let ca_bundle = network
+        .ca_bundle_pem()
+        .map_err(|err| AwsCredentialsError::InvalidNetworkSettings(format!("Failed to read CA bundle: {err}")))?;
+    if let Some(ca_bundle) = ca_bundle {
+        // The TLS provider panics on invalid certificates, so check them up front
+        reqwest::Certificate::from_pem_bundle(&ca_bundle)
+            .map_err(|err| AwsCredentialsError::InvalidNetworkSettings(format!("Invalid CA bundle: {err}")))?;
+        trust_store = trust_store.with_pem_certificate(ca_bundle);
+    }
+    let tls_context = TlsContext::builder()
+        .with_trust_store(trust_store)
+        .build()
+        .map_err(|err| AwsCredentialsError::InvalidNetworkSettings(format!("{err}")))?;
+
+    let http_client = HttpClientBuilder::new().build_with_connector_fn(move |settings, runtime_components| {
+        let mut connector_builder = ConnectorBuilder::default()
+            .tls_provider(tls::Provider::Rustls(CryptoMode::AwsLc))
+            .tls_context(tls_context.clone());
+        connector_builder.set_connector_settings(settings.cloned());
+        if let Some(components) = runtime_components {
+            connector_builder.set_sleep_impl(components.sleep_impl());
+        }
+        connector_builder.set_proxy_config(Some(proxy_config.clone()));
+        connector_builder.build()
+    });
+    Ok(Some(http_client))
+}
+
+async fn sts_client(role_info: &RoleInfo) -> Result<aws_sdk_sts::Client> {
+    let network = &role_info.network;
+    let mut config_loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+    if let Some(http_client) = http_client(network)? {
+        config_loader = config_loader.http_client(http_client);
+    }
+    let sts_endpoint = &role_info.sts_endpoint;
+    if let Some(endpoint_url) = &sts_endpoint.endpoint_url {
+        config_loader = config_loader.endpoint_url(endpoint_url);
+    }
+    // Only override when enabled, so that `AWS_USE_FIPS_ENDPOINT` and
+    // `AWS_USE_DUALSTACK_ENDPOINT` still apply otherwise
+    if sts_endpoint.use_fips {
+        config_loader = config_loader.use_fips(true);
+    }
+    if sts_endpoint.use_dual_stack {
+        config_loader = config_loader.use_dual_stack(true);
+    }
+    // Retries are done by `with_retries` so that they are logged
+    let config = config_loader
         .no_credentials()
         .region(Region::new(role_info.region.clone()))
+        .retry_config(RetryConfig::disabled())
+        .timeout_config(
+            TimeoutConfig::builder()
+                .connect_timeout(network.connect_timeout)
+                .operation_attempt_timeout(network.request_timeout)
+                .build(),
+        )
         .load()
         .await;
+    Ok(aws_sdk_sts::Client::new(&config))
+}
 
-    let principal_arn = format!("arn:aws:iam::{}:saml-provider/AzureAD", role_info.account_id);
-    let sts_client = aws_sdk_sts::Client::new(&config);
-    let result = sts_client.assume_role_with_saml()
-        .role_arn(role_info.role_arn())
-        .principal_arn(principal_arn)
-        .saml_assertion(saml_token)
-        .duration_seconds(role_info.duration)
-        .send()
-        .await?;
-    let aws_creds = result.credentials.unwrap();
+fn is_retryable<E: ProvideErrorMetadata>(error: &SdkError<E, HttpResponse>) -> bool {
+    match error {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => true,
+        SdkError::ServiceError(context) => {
+            context.raw().status().is_server_error()
+                || context
+                    .err()
+                    .code()
+                    .is_some_and(|code| RETRYABLE_ERROR_CODES.contains(&code))
+        }
+        _ => false,
+    }
+}
+
+fn temporary_credentials(role_info: &RoleInfo, aws_creds: Option<Credentials>) -> Result<TemporaryAwsCredentials> {
+    let aws_creds = aws_creds.ok_or(AwsCredentialsError::MissingCredentials)?;
     let expiration_time = aws_creds.expiration.to_chrono_utc()?;
     let creds = TemporaryAwsCredentialsBuilder::default()
         .version(DEFAULT_CREDS_VERSION)
@@ -49,3 +176,45 @@ pub async fn acquire_aws_credentials(role_info: &RoleInfo, saml_token: &str) ->
         .build()?;
     Ok(creds)
 }
+
+pub async fn acquire_aws_credentials(role_info: &RoleInfo, saml_token: &str) -> Result<TemporaryAwsCredentials> {
+    let principal_arn = format!(
+        "arn:{}:iam::{}:saml-provider/AzureAD",
+        role_info.aws_partition, role_info.account_id
+    );
+    let sts_client = sts_client(role_info).await?;
+    let request = sts_client.assume_role_with_saml()
+        .role_arn(role_info.role_arn())
+        .principal_arn(principal_arn)
+        .saml_assertion(saml_token)
+        .duration_seconds(role_info.duration);
+    let result = with_retries(
+        "AssumeRoleWithSAML",
+        role_info.network.max_attempts,
+        is_retryable,
+        || request.clone().send(),
+    )
+    .await
+    .map_err(Box::new)?;
+    temporary_credentials(role_info, result.credentials)
+}
+
+/// Assume the role with the Azure AD token directly. This requires an IAM OIDC identity provider
+/// for Azure AD on the account, but skips the token exchange service.
+pub async fn acquire_aws_credentials_with_web_identity(role_info: &RoleInfo, oidc_token: &str) -> Result<TemporaryAwsCredentials> {
+    let sts_client = sts_client(role_info).await?;
+    let request = sts_client.assume_role_with_web_identity()
+        .role_arn(role_info.role_arn())
+        .role_session_name(DEFAULT_ROLE_SESSION_NAME)
+        .web_identity_token(oidc_token)
+        .duration_seconds(role_info.duration);
+    let result = with_retries(
+        "AssumeRoleWithWebIdentity",
+        role_info.network.max_attempts,
+        is_retryable,
+        || request.clone().send(),
+    )
+    .await
+    .map_err(Box::new)?;
+    temporary_credentials(role_info, result.credentials)
+}
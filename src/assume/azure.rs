@@ -1,7 +1,24 @@
-// use std::sync::Arc;
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::sync::{Arc, LazyLock, Mutex};
 
-use azure_core::auth::TokenCredential;
-use azure_identity::DefaultAzureCredential;
+use azure_core::auth::{TokenCredential, TokenResponse};
+use azure_identity::{
+    DefaultAzureCredential, DefaultAzureCredentialEnum, EnvironmentCredential,
+    ImdsManagedIdentityCredential, TokenCredentialOptions,
+};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use inquire::Confirm;
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+use super::models::{OidcToken, SamlAssertion};
+use super::retry::with_retries;
+use crate::cache::{OidcTokenCache, SamlAssertionCache};
+use crate::defaults::{OIDC_TOKEN_MIN_REMAINING, SAML_ASSERTION_MIN_REMAINING};
+use crate::models::{AzureAccount, LoginMode, LoginSettings, NetworkSettings, RoleInfo};
 
 const AZ_CLIENT_ID: &str = "api://3cd4d944-d89b-401b-b2ae-fb1ece182362";
 const TOKEN_EXCHANGE_URL: &str = "https://ws-iam-commontools-oidc2saml.azurewebsites.net/api/TokenExchange/SAMLResponse";
@@ -9,6 +26,10 @@ const IDENTIFIER_URI_BASE: &str = "https://signin.aws.amazon.com/saml/";
 
 pub type Result<T> = std::result::Result<T, AzureAdTokenError>;
 
+// SAML assertions acquired by this process, keyed by account and Azure account
+static SAML_ASSERTIONS: LazyLock<Mutex<HashMap<(String, AzureAccount), SamlAssertion>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
 #[derive(Debug, thiserror::Error)]
 pub enum AzureAdTokenError {
     #[error(transparent)]
@@ -17,62 +38,668 @@ pub enum AzureAdTokenError {
     AcquireSamlTokenFailed(#[from] reqwest::Error),
     #[error("Azure Login Process Error: {0}")]
     AzureLoginProcessError(String),
+    #[error("Azure CLI Error: {0}")]
+    AzureCliError(String),
+    #[error("No Azure CLI account found for {0}")]
+    AzureAccountNotFound(String),
+    #[error("Invalid SAML assertion: {0}")]
+    InvalidSamlAssertion(String),
+    #[error("The token exchange service rejected the Azure AD token (HTTP {status}). Run `{command_line}` to refresh your Azure login, and check that you have been granted access to the token exchange application.")]
+    Unauthorized {
+        status: reqwest::StatusCode,
+        command_line: String,
+    },
+    #[error("The token exchange application is not configured for account {account_id} (HTTP {status}). Follow the steps in the TokenExchange documentation for this account: https://github.com/LEGO/IAM-CommonTools-OIDC2SAML-TokenExchange/tree/main/Examples")]
+    AppNotConfiguredForAccount {
+        account_id: String,
+        status: reqwest::StatusCode,
+    },
+    #[error("The token exchange service is unavailable (HTTP {0}). Try again later.")]
+    ServiceUnavailable(reqwest::StatusCode),
+    #[error("The token exchange service returned an unexpected response (HTTP {status}): {message}")]
+    MalformedResponse {
+        status: reqwest::StatusCode,
+        message: String,
+    },
+    #[error("Invalid network settings: {0}")]
+    InvalidNetworkSettings(String),
+    #[error("Not logged in to Azure and {reason}. Run `{command_line}` in a terminal and try again.")]
+    LoginRequired { reason: String, command_line: String },
+    #[error("Azure login command `{command_line}` failed ({status}). Check the output above and try again.")]
+    LoginFailed { command_line: String, status: std::process::ExitStatus },
+}
+
+/// An HTTP client with the timeouts, proxy and CA bundle from the network settings. Without an
+/// explicit proxy the `HTTPS_PROXY` and `NO_PROXY` environment variables are used.
+pub(super) fn http_client(network: &NetworkSettings) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(network.connect_timeout)
+        .timeout(network.request_timeout);
+    if let Some(proxy) = &network.proxy {
+        let proxy = reqwest::Proxy::all(proxy)
+            .map_err(|err| AzureAdTokenError::InvalidNetworkSettings(format!("Invalid proxy '{proxy}': {err}")))?
+            .no_proxy(reqwest::NoProxy::from_env());
+        builder = builder.proxy(proxy);
+    }
+    let ca_bundle = network
+        .ca_bundle_pem()
+        .map_err(|err| AzureAdTokenError::InvalidNetworkSettings(format!("Failed to read CA bundle: {err}")))?;
+    if let Some(ca_bundle) = ca_bundle {
+        for certificate in reqwest::Certificate::from_pem_bundle(&ca_bundle)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    Ok(builder.build()?)
+}
+
+fn oidc_token_from_response(res: TokenResponse) -> OidcToken {
+    let expiration = DateTime::<Utc>::from_timestamp(res.expires_on.unix_timestamp(), 0)
+        .unwrap_or_else(Utc::now);
+    OidcToken {
+        token: res.token.secret().to_string(),
+        audience: AZ_CLIENT_ID.to_string(),
+        expiration,
+    }
+}
+
+pub async fn oidc_token(azure_account: &AzureAccount, network: &NetworkSettings) -> Result<OidcToken> {
+    if !azure_account.is_default() {
+        return az_cli_token(azure_account, network).await;
+    }
+    // The Azure CLI credential of `DefaultAzureCredential` can neither be given the proxy and CA
+    // bundle nor be stopped at the deadline, as it waits for `az` blocking the runtime. So it is
+    // replaced by `az_cli_token`, which can.
+    let http_client = Arc::new(http_client(network)?);
+    let credential = DefaultAzureCredential::with_sources(vec![
+        DefaultAzureCredentialEnum::Environment(EnvironmentCredential::new(
+            http_client.clone(),
+            TokenCredentialOptions::default(),
+        )),
+        DefaultAzureCredentialEnum::ManagedIdentity(ImdsManagedIdentityCredential::new(http_client)),
+    ]);
+    match credential.get_token(AZ_CLIENT_ID).await {
+        Ok(res) => Ok(oidc_token_from_response(res)),
+        Err(error) => {
+            debug!("No environment or managed identity credential: {error}");
+            az_cli_token(azure_account, network).await
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AzCliAccount {
+    id: String,
+    tenant_id: String,
+    user: AzCliUser,
+}
+
+#[derive(Debug, Deserialize)]
+struct AzCliUser {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AzCliAccessToken {
+    access_token: String,
+    /// Local time, e.g. `2023-09-05 12:34:56.000000`
+    expires_on: String,
+    /// Unix timestamp, only returned by newer versions of the Azure CLI
+    #[serde(rename = "expires_on")]
+    expires_on_timestamp: Option<i64>,
+}
+
+impl AzCliAccessToken {
+    fn expiration(&self) -> Result<DateTime<Utc>> {
+        if let Some(timestamp) = self.expires_on_timestamp {
+            if let Some(expiration) = DateTime::<Utc>::from_timestamp(timestamp, 0) {
+                return Ok(expiration);
+            }
+        }
+        NaiveDateTime::parse_from_str(&self.expires_on, "%Y-%m-%d %H:%M:%S%.f")
+            .ok()
+            .and_then(|expiration| Local.from_local_datetime(&expiration).earliest())
+            .map(|expiration| expiration.with_timezone(&Utc))
+            .ok_or_else(|| {
+                AzureAdTokenError::AzureCliError(format!("Unable to parse expiresOn '{}'", self.expires_on))
+            })
+    }
+}
+
+// Child processes are run asynchronously and killed when dropped, so that the deadline for
+// acquiring credentials also stops a hanging `az` or login command
+async fn run_az(args: &[&str], network: &NetworkSettings) -> Result<Vec<u8>> {
+    // Thank you Klaus Legarth for the Windows support
+    let mut az_command = if std::env::consts::OS == "windows" {
+        let mut command = tokio::process::Command::new("cmd");
+        command.arg("/C");
+        command.arg("az");
+        command
+    } else {
+        tokio::process::Command::new("az")
+    };
+    let output = az_command
+        .args(args)
+        .envs(network.child_process_env())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|err| AzureAdTokenError::AzureCliError(format!("{err}")))?;
+    if !output.status.success() {
+        return Err(AzureAdTokenError::AzureCliError(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// Find the Azure CLI subscription belonging to the given user, and tenant if given.
+async fn az_cli_subscription(tenant_id: Option<&str>, username: &str, network: &NetworkSettings) -> Result<String> {
+    let output = run_az(&["account", "list", "--all", "--output", "json"], network).await?;
+    let accounts: Vec<AzCliAccount> = serde_json::from_slice(&output)
+        .map_err(|err| AzureAdTokenError::AzureCliError(format!("{err}")))?;
+    accounts
+        .into_iter()
+        .find(|account| {
+            account.user.name.eq_ignore_ascii_case(username)
+                && tenant_id.is_none_or(|tenant_id| account.tenant_id == tenant_id)
+        })
+        .map(|account| account.id)
+        .ok_or_else(|| {
+            AzureAdTokenError::AzureAccountNotFound(match tenant_id {
+                Some(tenant_id) => format!("user {username} in tenant {tenant_id}"),
+                None => format!("user {username}"),
+            })
+        })
 }
 
-pub async fn oidc_token() -> Result<String> {
-    // let az_cli_credential_arc = Arc::new(DefaultAzureCredential::default());
-    // let token_credential = azure_identity::AutoRefreshingTokenCredential::new(az_cli_credential_arc);
-    // log::debug!("Getting Token");
-    // let response = token_credential.get_token(AZ_CLIENT_ID).await?;
-    // log::debug!("Done Getting Token");
-    // let oidc_token = response.token.secret();
-    // Ok(oidc_token.to_string())
-    let az_cli_credential = DefaultAzureCredential::default();
-    let res = az_cli_credential.get_token(AZ_CLIENT_ID).await?;
-    let token = res.token.secret();
-    Ok(token.to_string())
+/// Acquire a token from the Azure CLI for a specific tenant and/or user instead of the active
+/// account.
+async fn az_cli_token(azure_account: &AzureAccount, network: &NetworkSettings) -> Result<OidcToken> {
+    let mut args = vec!["account", "get-access-token", "--output", "json", "--resource", AZ_CLIENT_ID];
+    let subscription;
+    match (&azure_account.tenant_id, &azure_account.username) {
+        (tenant_id, Some(username)) => {
+            subscription = az_cli_subscription(tenant_id.as_deref(), username, network).await?;
+            args.extend(["--subscription", &subscription]);
+        }
+        (Some(tenant_id), None) => args.extend(["--tenant", tenant_id]),
+        (None, None) => {}
+    }
+    let output = run_az(&args, network).await?;
+    let token: AzCliAccessToken = serde_json::from_slice(&output)
+        .map_err(|err| AzureAdTokenError::AzureCliError(format!("{err}")))?;
+    Ok(OidcToken {
+        expiration: token.expiration()?,
+        token: token.access_token,
+        audience: AZ_CLIENT_ID.to_string(),
+    })
+}
+
+impl AzureAdTokenError {
+    /// Whether the token exchange may succeed when retried.
+    fn is_retryable(&self) -> bool {
+        match self {
+            AzureAdTokenError::AcquireSamlTokenFailed(error) => {
+                error.is_timeout() || error.is_connect() || error.is_request()
+            }
+            AzureAdTokenError::ServiceUnavailable(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Exchange the OIDC token for a SAML assertion. `login_command_line` is suggested for refreshing
+/// the Azure login when the token is rejected.
+pub async fn saml_token_from_oidc_token(
+    account_id: &str,
+    oidc_token: &str,
+    network: &NetworkSettings,
+    login_command_line: &str,
+) -> Result<String> {
+    let client = http_client(network)?;
+    with_retries(
+        "Token exchange",
+        network.max_attempts,
+        AzureAdTokenError::is_retryable,
+        || exchange_oidc_token(&client, account_id, oidc_token, login_command_line),
+    )
+    .await
 }
 
-pub async fn saml_token_from_oidc_token(account_id: &str, oidc_token: &str) -> Result<String> {
-    let client = reqwest::Client::new();
+async fn exchange_oidc_token(
+    client: &reqwest::Client,
+    account_id: &str,
+    oidc_token: &str,
+    login_command_line: &str,
+) -> Result<String> {
     let mut identifier_uri = String::from(IDENTIFIER_URI_BASE);
     identifier_uri.push_str(account_id);
-    let saml_token = client
+    let response = client
         .get(TOKEN_EXCHANGE_URL)
         .query(&[("IdentifierUri", identifier_uri)])
         .bearer_auth(oidc_token)
         .send()
-        .await?
-        .text()
         .await?;
-    Ok(saml_token)
+    let status = response.status();
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let body = response.text().await?;
+    debug!("Token exchange responded with HTTP {status} ({content_type})");
+    classify(status, &content_type, body, account_id, login_command_line)
+}
+
+/// The SAML response in the body of a token exchange response, or the error the response stands
+/// for.
+fn classify(
+    status: reqwest::StatusCode,
+    content_type: &str,
+    body: String,
+    account_id: &str,
+    login_command_line: &str,
+) -> Result<String> {
+    match status {
+        reqwest::StatusCode::UNAUTHORIZED => {
+            return Err(AzureAdTokenError::Unauthorized {
+                status,
+                command_line: login_command_line.to_string(),
+            })
+        }
+        reqwest::StatusCode::FORBIDDEN | reqwest::StatusCode::NOT_FOUND => {
+            return Err(AzureAdTokenError::AppNotConfiguredForAccount {
+                account_id: account_id.to_string(),
+                status,
+            })
+        }
+        reqwest::StatusCode::TOO_MANY_REQUESTS => return Err(AzureAdTokenError::ServiceUnavailable(status)),
+        status if status.is_server_error() => return Err(AzureAdTokenError::ServiceUnavailable(status)),
+        status if !status.is_success() => {
+            return Err(AzureAdTokenError::MalformedResponse {
+                status,
+                message: truncated(&body),
+            })
+        }
+        _ => {}
+    }
+    if content_type.starts_with("text/html") {
+        return Err(AzureAdTokenError::MalformedResponse {
+            status,
+            message: format!("Expected a SAML response, got an HTML page: {}", truncated(&body)),
+        });
+    }
+    validate_saml_response(&body).map_err(|message| AzureAdTokenError::MalformedResponse { status, message })?;
+    Ok(body)
+}
+
+/// The start of a response body for use in error messages.
+fn truncated(body: &str) -> String {
+    const MAX_LENGTH: usize = 200;
+    let body = body.trim();
+    match body.char_indices().nth(MAX_LENGTH) {
+        Some((index, _)) => format!("{}...", &body[..index]),
+        None => body.to_string(),
+    }
+}
+
+/// Check that the body is a base64 encoded SAML response containing an assertion.
+fn validate_saml_response(body: &str) -> std::result::Result<(), String> {
+    let decoded = BASE64
+        .decode(body.trim())
+        .map_err(|err| format!("Expected a base64 encoded SAML response ({err}): {}", truncated(body)))?;
+    let xml = String::from_utf8(decoded)
+        .map_err(|err| format!("The SAML response is not valid UTF-8: {err}"))?;
+    let xml = xml.trim_start_matches('\u{feff}').trim_start();
+    let mut elements = element_names(xml);
+    if !xml.starts_with('<') || elements.next() != Some("Response") {
+        return Err(format!("The decoded response is not a SAML response: {}", truncated(xml)));
+    }
+    if !elements.any(|name| name == "Assertion" || name == "EncryptedAssertion") {
+        return Err(String::from("The SAML response contains no assertion"));
+    }
+    Ok(())
 }
 
-pub async fn saml_token(account_id: &str) -> Result<String> {
-    let res = oidc_token().await;
-    match res {
-        Ok(oidc_token) => saml_token_from_oidc_token(account_id, &oidc_token).await,
-        Err(_error) => {
-            // Thank you Klaus Legarth for the Windows support
-            let mut az_command = if std::env::consts::OS == "windows" {
-                let mut command = std::process::Command::new("cmd");
-                command.arg("/C");
-                command.arg("az");
-                command
+/// The names of the elements of the XML document in order, without namespace prefixes. The XML
+/// declaration, comments, and closing tags are skipped.
+fn element_names(xml: &str) -> impl Iterator<Item = &str> {
+    xml.split('<')
+        .skip(1)
+        .filter(|tag| !tag.starts_with(['/', '?', '!']))
+        .map(|tag| tag.split([' ', '\t', '\r', '\n', '>', '/']).next().unwrap_or_default())
+        .map(|name| name.rsplit(':').next().unwrap_or_default())
+}
+
+fn is_interactive() -> bool {
+    std::io::stdin().is_terminal() && std::io::stderr().is_terminal()
+}
+
+/// Decide whether the login command may be run according to the login mode.
+fn check_login_allowed(login: &LoginSettings, azure_account: &AzureAccount) -> Result<()> {
+    let login_required = |reason: &str| AzureAdTokenError::LoginRequired {
+        reason: reason.to_string(),
+        command_line: login.command_line(azure_account),
+    };
+    match login.mode {
+        LoginMode::Never => Err(login_required("login is disabled (--login never)")),
+        LoginMode::Auto if !is_interactive() => Err(login_required(
+            "no terminal is available for logging in, e.g. when running as `credential_process`",
+        )),
+        LoginMode::Auto => Ok(()),
+        LoginMode::Prompt if !is_interactive() => Err(login_required(
+            "no terminal is available for the login prompt",
+        )),
+        LoginMode::Prompt => {
+            let do_login = Confirm::new(&format!("Not logged in to Azure. Run `{}`?", login.command_line(azure_account)))
+                .with_default(true)
+                .with_placeholder("y/yes or n/no")
+                .prompt()
+                .unwrap_or(false);
+            if do_login {
+                Ok(())
             } else {
-                std::process::Command::new("az")
-            };
-            az_command
-                .arg("login")
-                .status()
-                .map_err(|err| AzureAdTokenError::AzureLoginProcessError(format!("{err}")))?
-                .success()
-                .then_some(oidc_token().await?)
-                .map(|t| async move {
-                    saml_token_from_oidc_token(account_id, &t).await
-                })
-                .unwrap()
-            .await
+                Err(login_required("login was declined"))
+            }
+        }
+    }
+}
+
+async fn run_login_command(login: &LoginSettings, azure_account: &AzureAccount, network: &NetworkSettings) -> Result<()> {
+    // Thank you Klaus Legarth for the Windows support
+    let mut login_command = if std::env::consts::OS == "windows" {
+        let mut command = tokio::process::Command::new("cmd");
+        command.arg("/C");
+        command.arg(&login.command);
+        command
+    } else {
+        tokio::process::Command::new(&login.command)
+    };
+    info!("Running login command `{}`", login.command_line(azure_account));
+    let status = login_command
+        .args(login.command_args(azure_account))
+        .envs(network.child_process_env())
+        .kill_on_drop(true)
+        .status()
+        .await
+        .map_err(|err| AzureAdTokenError::AzureLoginProcessError(format!("{err}")))?;
+    if !status.success() {
+        return Err(AzureAdTokenError::LoginFailed {
+            command_line: login.command_line(azure_account),
+            status,
+        });
+    }
+    Ok(())
+}
+
+/// Acquire an Azure AD token, running the login command first if no token can be acquired and
+/// the login mode allows it.
+async fn acquire_oidc_token(role_info: &RoleInfo) -> Result<OidcToken> {
+    let RoleInfo { login, azure_account, network, .. } = role_info;
+    match oidc_token(azure_account, network).await {
+        Ok(oidc_token) => Ok(oidc_token),
+        Err(error) => {
+            warn!("Failed to acquire Azure AD token: {error}");
+            check_login_allowed(login, azure_account)?;
+            run_login_command(login, azure_account, network).await?;
+            oidc_token(azure_account, network).await
+        }
+    }
+}
+
+/// Get an Azure AD token from the cache, or acquire and cache a new one if the cached token is
+/// missing or about to expire, or `force` is set.
+pub async fn oidc_token_with_login(role_info: &RoleInfo, force: bool) -> Result<String> {
+    let token_cache = OidcTokenCache::new(&role_info.cache_settings, &role_info.azure_account, AZ_CLIENT_ID)
+        .map_err(|error| warn!("Can not cache Azure AD token: {error}"))
+        .ok();
+    let min_remaining = chrono::Duration::seconds(OIDC_TOKEN_MIN_REMAINING);
+    let cached_token = token_cache
+        .as_ref()
+        .filter(|_| !force)
+        .and_then(|cache| cache.token().ok());
+    if let Some(cached_token) = cached_token {
+        if cached_token.audience == AZ_CLIENT_ID && cached_token.is_fresh(min_remaining) {
+            debug!("Using cached Azure AD token expiring at {}", cached_token.expiration);
+            return Ok(cached_token.token);
+        }
+        debug!("Cached Azure AD token is expired or about to expire");
+    }
+
+    let oidc_token = acquire_oidc_token(role_info).await?;
+    if let Some(token_cache) = &token_cache {
+        if let Err(error) = token_cache.store_token(&oidc_token) {
+            warn!("Failed to cache Azure AD token: {error}");
+        }
+    }
+    Ok(oidc_token.token)
+}
+
+/// The earliest `NotOnOrAfter` of the conditions and subject confirmations in a base64 encoded
+/// SAML response.
+fn saml_not_on_or_after(saml_token: &str) -> Result<DateTime<Utc>> {
+    let decoded = BASE64
+        .decode(saml_token.trim())
+        .map_err(|err| AzureAdTokenError::InvalidSamlAssertion(format!("{err}")))?;
+    let xml = String::from_utf8_lossy(&decoded);
+    xml.split("NotOnOrAfter=\"")
+        .skip(1)
+        .filter_map(|rest| rest.split('"').next())
+        .filter_map(|value| DateTime::parse_from_rfc3339(value).ok())
+        .map(|value| value.with_timezone(&Utc))
+        .min()
+        .ok_or_else(|| AzureAdTokenError::InvalidSamlAssertion(String::from("No NotOnOrAfter found")))
+}
+
+/// Remove the cached Azure AD token, e.g. after it has been rejected, so that a new one is
+/// acquired next time.
+pub fn forget_oidc_token(role_info: &RoleInfo) {
+    let removed = OidcTokenCache::new(&role_info.cache_settings, &role_info.azure_account, AZ_CLIENT_ID)
+        .and_then(|cache| cache.remove());
+    if let Err(error) = removed {
+        warn!("Failed to remove the cached Azure AD token: {error}");
+    }
+}
+
+/// Remove the SAML assertion for the account from memory and the cache, e.g. after it has been
+/// rejected, so that a new one is acquired next time.
+pub fn forget_saml_assertion(role_info: &RoleInfo) {
+    let key = (role_info.account_id.clone(), role_info.azure_account.clone());
+    if let Ok(mut assertions) = SAML_ASSERTIONS.lock() {
+        assertions.remove(&key);
+    }
+    if role_info.cache_saml_assertion {
+        let removed = SamlAssertionCache::new(&role_info.cache_settings, &role_info.account_id, &role_info.azure_account)
+            .and_then(|cache| cache.remove());
+        if let Err(error) = removed {
+            warn!("Failed to remove the cached SAML assertion: {error}");
+        }
+    }
+}
+
+fn cached_saml_assertion(role_info: &RoleInfo) -> Option<SamlAssertion> {
+    let min_remaining = chrono::Duration::seconds(SAML_ASSERTION_MIN_REMAINING);
+    let key = (role_info.account_id.clone(), role_info.azure_account.clone());
+    let in_memory = SAML_ASSERTIONS.lock().ok()?.get(&key).cloned();
+    let on_disk = || {
+        SamlAssertionCache::new(&role_info.cache_settings, &role_info.account_id, &role_info.azure_account)
+            .and_then(|cache| cache.assertion())
+            .ok()
+    };
+    in_memory
+        .or_else(|| role_info.cache_saml_assertion.then(on_disk).flatten())
+        .filter(|assertion| assertion.account_id == role_info.account_id && assertion.is_fresh(min_remaining))
+}
+
+fn store_saml_assertion(role_info: &RoleInfo, assertion: &SamlAssertion) {
+    let key = (role_info.account_id.clone(), role_info.azure_account.clone());
+    if let Ok(mut assertions) = SAML_ASSERTIONS.lock() {
+        assertions.insert(key, assertion.clone());
+    }
+    if role_info.cache_saml_assertion {
+        let stored = SamlAssertionCache::new(&role_info.cache_settings, &role_info.account_id, &role_info.azure_account)
+            .and_then(|cache| cache.store_assertion(assertion));
+        if let Err(error) = stored {
+            warn!("Failed to cache SAML assertion: {error}");
+        }
+    }
+}
+
+/// Get a SAML assertion for the account, reusing a previously acquired one until it expires
+/// unless `force` is set.
+pub async fn saml_token(role_info: &RoleInfo, force: bool) -> Result<String> {
+    if let Some(assertion) = cached_saml_assertion(role_info).filter(|_| !force) {
+        debug!("Using cached SAML assertion valid until {}", assertion.not_on_or_after);
+        return Ok(assertion.assertion);
+    }
+
+    let oidc_token = oidc_token_with_login(role_info, force).await?;
+    let saml_token = saml_token_from_oidc_token(
+        &role_info.account_id,
+        &oidc_token,
+        &role_info.network,
+        &role_info.login.command_line(&role_info.azure_account),
+    )
+    .await
+    .inspect_err(|error| {
+        // Logging in again does not help as long as the rejected token is reused
+        if matches!(error, AzureAdTokenError::Unauthorized { .. }) {
+            forget_oidc_token(role_info);
+            forget_saml_assertion(role_info);
+        }
+    })?;
+    match saml_not_on_or_after(&saml_token) {
+        Ok(not_on_or_after) => store_saml_assertion(
+            role_info,
+            &SamlAssertion {
+                assertion: saml_token.clone(),
+                account_id: role_info.account_id.clone(),
+                not_on_or_after,
+            },
+        ),
+        Err(error) => warn!("Not caching SAML assertion: {error}"),
+    }
+    Ok(saml_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::*;
+
+    // A SAML response as issued by Entra ID, shortened
+    const SAML_RESPONSE: &str = r#"<samlp:Response ID="_8e8dc5f6" Version="2.0" IssueInstant="2026-10-18T12:00:00.000Z" Destination="https://signin.aws.amazon.com/saml" xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol">
+  <Issuer xmlns="urn:oasis:names:tc:SAML:2.0:assertion">https://sts.windows.net/00000000-0000-0000-0000-000000000000/</Issuer>
+  <samlp:Status><samlp:StatusCode Value="urn:oasis:names:tc:SAML:2.0:status:Success"/></samlp:Status>
+  <Assertion ID="_d71a3a8e" IssueInstant="2026-10-18T12:00:00.000Z" Version="2.0" xmlns="urn:oasis:names:tc:SAML:2.0:assertion">
+    <Issuer>https://sts.windows.net/00000000-0000-0000-0000-000000000000/</Issuer>
+    <Subject>
+      <NameID Format="urn:oasis:names:tc:SAML:2.0:nameid-format:persistent">user@example.com</NameID>
+      <SubjectConfirmation Method="urn:oasis:names:tc:SAML:2.0:cm:bearer">
+        <SubjectConfirmationData NotOnOrAfter="2026-10-18T12:05:00.000Z" Recipient="https://signin.aws.amazon.com/saml"/>
+      </SubjectConfirmation>
+    </Subject>
+    <Conditions NotBefore="2026-10-18T11:55:00.000Z" NotOnOrAfter="2026-10-18T13:00:00.000Z">
+      <AudienceRestriction><Audience>https://signin.aws.amazon.com/saml</Audience></AudienceRestriction>
+    </Conditions>
+    <AttributeStatement>
+      <Attribute Name="https://aws.amazon.com/SAML/Attributes/Role">
+        <AttributeValue>arn:aws:iam::123456789012:role/deployer,arn:aws:iam::123456789012:saml-provider/AzureAD</AttributeValue>
+      </Attribute>
+    </AttributeStatement>
+  </Assertion>
+</samlp:Response>"#;
+
+    fn classify_ok(body: &str) -> Result<String> {
+        classify(StatusCode::OK, "text/plain", body.to_string(), "123456789012", "az login")
+    }
+
+    fn malformed_message(result: Result<String>) -> String {
+        match result {
+            Err(AzureAdTokenError::MalformedResponse { message, .. }) => message,
+            other => panic!("expected a malformed response, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn accepts_saml_responses_with_assertion() {
+        let body = BASE64.encode(SAML_RESPONSE);
+        assert_eq!(classify_ok(&body).unwrap(), body);
+        let with_declaration = BASE64.encode(format!("\u{feff}<?xml version=\"1.0\"?>\n{SAML_RESPONSE}"));
+        assert!(classify_ok(&with_declaration).is_ok());
+    }
+
+    #[test]
+    fn rejects_html_pages() {
+        let page = "<!DOCTYPE html><html><body>Sign in to your account</body></html>";
+        let result = classify(StatusCode::OK, "text/html; charset=utf-8", page.to_string(), "123456789012", "az login");
+        assert!(malformed_message(result).contains("HTML page"));
+        // Without the content type the page is not base64
+        assert!(malformed_message(classify_ok(page)).contains("base64"));
+    }
+
+    #[test]
+    fn rejects_bodies_that_are_not_saml_responses() {
+        assert!(malformed_message(classify_ok("not base64!")).contains("base64"));
+        let json = BASE64.encode(r#"{"Response":"Assertion"}"#);
+        assert!(malformed_message(classify_ok(&json)).contains("not a SAML response"));
+        let other_xml = BASE64.encode("<ResponseList><Assertion/></ResponseList>");
+        assert!(malformed_message(classify_ok(&other_xml)).contains("not a SAML response"));
+        let without_assertion = BASE64.encode(
+            r#"<samlp:Response xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol"><samlp:Status/></samlp:Response>"#,
+        );
+        assert_eq!(malformed_message(classify_ok(&without_assertion)), "The SAML response contains no assertion");
+    }
+
+    #[test]
+    fn maps_error_statuses() {
+        let classify_status = |status| classify(status, "application/json", String::from("{}"), "123456789012", "az login --tenant t");
+        match classify_status(StatusCode::UNAUTHORIZED) {
+            Err(AzureAdTokenError::Unauthorized { command_line, .. }) => assert_eq!(command_line, "az login --tenant t"),
+            other => panic!("expected unauthorized, got {other:?}"),
         }
+        for status in [StatusCode::FORBIDDEN, StatusCode::NOT_FOUND] {
+            assert!(matches!(
+                classify_status(status),
+                Err(AzureAdTokenError::AppNotConfiguredForAccount { ref account_id, .. }) if account_id == "123456789012"
+            ));
+        }
+        for status in [StatusCode::TOO_MANY_REQUESTS, StatusCode::INTERNAL_SERVER_ERROR, StatusCode::BAD_GATEWAY] {
+            let result = classify_status(status);
+            assert!(matches!(result, Err(AzureAdTokenError::ServiceUnavailable(_))));
+            assert!(result.unwrap_err().is_retryable());
+        }
+        for status in [StatusCode::BAD_REQUEST, StatusCode::CONFLICT] {
+            assert!(matches!(classify_status(status), Err(AzureAdTokenError::MalformedResponse { .. })));
+        }
+    }
+
+    #[test]
+    fn takes_the_earliest_not_on_or_after() {
+        let subject_confirmation_first = BASE64.encode(SAML_RESPONSE);
+        assert_eq!(
+            saml_not_on_or_after(&subject_confirmation_first).unwrap(),
+            Utc.with_ymd_and_hms(2026, 10, 18, 12, 5, 0).unwrap()
+        );
+        // Conditions may be the earlier one as well
+        let conditions_first = BASE64.encode(SAML_RESPONSE.replace("2026-10-18T13:00:00.000Z", "2026-10-18T12:01:30.000Z"));
+        assert_eq!(
+            saml_not_on_or_after(&conditions_first).unwrap(),
+            Utc.with_ymd_and_hms(2026, 10, 18, 12, 1, 30).unwrap()
+        );
+        let without = BASE64.encode(SAML_RESPONSE.replace("NotOnOrAfter", "NotBefore"));
+        assert!(matches!(saml_not_on_or_after(&without), Err(AzureAdTokenError::InvalidSamlAssertion(_))));
+        assert!(matches!(saml_not_on_or_after("not base64!"), Err(AzureAdTokenError::InvalidSamlAssertion(_))));
+    }
+
+    #[test]
+    fn truncates_long_bodies() {
+        assert_eq!(truncated("  short \n"), "short");
+        let long = "é".repeat(250);
+        assert_eq!(truncated(&long), format!("{}...", "é".repeat(200)));
     }
 }
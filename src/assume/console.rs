@@ -0,0 +1,221 @@
+use log::debug;
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+
+use super::azure::http_client;
+use super::models::TemporaryAwsCredentials;
+use super::retry::with_retries;
+use crate::models::NetworkSettings;
+
+pub type Result<T> = std::result::Result<T, ConsoleError>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConsoleError {
+    #[error(transparent)]
+    RequestFailed(#[from] reqwest::Error),
+    #[error("The federation endpoint rejected the credentials (HTTP {0}). Check that the session duration does not exceed the maximum session duration of the role.")]
+    Rejected(StatusCode),
+    #[error("The federation endpoint is unavailable (HTTP {0}). Try again later.")]
+    ServiceUnavailable(StatusCode),
+    #[error("The federation endpoint returned an unexpected response (HTTP {status}): {message}")]
+    MalformedResponse { status: StatusCode, message: String },
+    #[error("No federation endpoint for partition '{0}'")]
+    UnknownPartition(String),
+    #[error("Invalid destination '{0}'. Give a service, e.g. 'ec2', a console path, e.g. 's3/buckets', or an https URL.")]
+    InvalidDestination(String),
+    #[error("Invalid network settings: {0}")]
+    InvalidNetworkSettings(String),
+    #[error("Failed to open the browser: {0}")]
+    OpenBrowserFailed(std::io::Error),
+}
+
+impl ConsoleError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            ConsoleError::RequestFailed(error) => {
+                error.is_timeout() || error.is_connect() || error.is_request()
+            }
+            ConsoleError::ServiceUnavailable(_) => true,
+            _ => false,
+        }
+    }
+}
+
+struct FederationEndpoints {
+    partition: &'static str,
+    sign_in: &'static str,
+    console: &'static str,
+}
+
+const FEDERATION_ENDPOINTS: [FederationEndpoints; 3] = [
+    FederationEndpoints {
+        partition: "aws",
+        sign_in: "https://signin.aws.amazon.com",
+        console: "https://console.aws.amazon.com",
+    },
+    FederationEndpoints {
+        partition: "aws-cn",
+        sign_in: "https://signin.amazonaws.cn",
+        console: "https://console.amazonaws.cn",
+    },
+    FederationEndpoints {
+        partition: "aws-us-gov",
+        sign_in: "https://signin.amazonaws-us-gov.com",
+        console: "https://console.amazonaws-us-gov.com",
+    },
+];
+
+fn federation_endpoints(aws_partition: &str) -> Result<&'static FederationEndpoints> {
+    FEDERATION_ENDPOINTS
+        .iter()
+        .find(|endpoints| endpoints.partition == aws_partition)
+        .ok_or_else(|| ConsoleError::UnknownPartition(aws_partition.to_string()))
+}
+
+/// Options for the console session.
+#[derive(Clone, Debug, Default)]
+pub struct ConsoleOptions {
+    /// A service, e.g. `ec2`, a console path, e.g. `s3/buckets`, or a full console URL. The
+    /// default is the console home.
+    pub destination: Option<String>,
+    /// URL the console links to when the session expires.
+    pub issuer: Option<String>,
+    /// Console session duration in seconds.
+    pub session_duration: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SignInTokenResponse {
+    signin_token: String,
+}
+
+fn destination_url(endpoints: &FederationEndpoints, region: &str, destination: Option<&str>) -> Result<String> {
+    let invalid = || ConsoleError::InvalidDestination(destination.unwrap_or_default().to_string());
+    let url = match destination.map(|destination| destination.trim_start_matches('/')) {
+        None | Some("") => format!("{}/console/home?region={region}", endpoints.console),
+        Some(url) if url.contains("://") => {
+            if !url.starts_with("https://") {
+                return Err(invalid());
+            }
+            url.to_string()
+        }
+        Some(service) if !service.contains(['/', '?', '#']) => {
+            format!("{}/{service}/home?region={region}", endpoints.console)
+        }
+        Some(path) => format!("{}/{path}", endpoints.console),
+    };
+    Url::parse(&url).map_err(|_| invalid())?;
+    Ok(url)
+}
+
+async fn sign_in_token(
+    client: &reqwest::Client,
+    endpoints: &FederationEndpoints,
+    session: &str,
+    session_duration: Option<u32>,
+) -> Result<String> {
+    let mut query = vec![("Action", "getSigninToken".to_string()), ("Session", session.to_string())];
+    if let Some(session_duration) = session_duration {
+        query.push(("SessionDuration", session_duration.to_string()));
+    }
+    let response = client
+        .get(format!("{}/federation", endpoints.sign_in))
+        .query(&query)
+        .send()
+        .await?;
+    let status = response.status();
+    let body = response.text().await?;
+    debug!("Federation endpoint responded with HTTP {status}");
+
+    match status {
+        StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            return Err(ConsoleError::Rejected(status))
+        }
+        StatusCode::TOO_MANY_REQUESTS => return Err(ConsoleError::ServiceUnavailable(status)),
+        status if status.is_server_error() => return Err(ConsoleError::ServiceUnavailable(status)),
+        status if !status.is_success() => {
+            return Err(ConsoleError::MalformedResponse {
+                status,
+                message: body.chars().take(200).collect(),
+            })
+        }
+        _ => {}
+    }
+    let response: SignInTokenResponse =
+        serde_json::from_str(&body).map_err(|err| ConsoleError::MalformedResponse {
+            status,
+            message: err.to_string(),
+        })?;
+    Ok(response.signin_token)
+}
+
+/// Exchange the credentials for a sign-in token at the federation endpoint of the partition and
+/// build the console sign-in URL from it. The URL is valid for 15 minutes.
+pub async fn sign_in_url(
+    credentials: &TemporaryAwsCredentials,
+    aws_partition: &str,
+    region: &str,
+    network: &NetworkSettings,
+    options: &ConsoleOptions,
+) -> Result<String> {
+    let endpoints = federation_endpoints(aws_partition)?;
+    let destination = destination_url(endpoints, region, options.destination.as_deref())?;
+    let session = serde_json::json!({
+        "sessionId": credentials.access_key_id,
+        "sessionKey": credentials.secret_access_key,
+        "sessionToken": credentials.session_token,
+    })
+    .to_string();
+    let client = http_client(network).map_err(|err| ConsoleError::InvalidNetworkSettings(err.to_string()))?;
+    let token = with_retries(
+        "Getting the sign-in token",
+        network.max_attempts,
+        ConsoleError::is_retryable,
+        || sign_in_token(&client, endpoints, &session, options.session_duration),
+    )
+    .await?;
+
+    let mut url = Url::parse(&format!("{}/federation", endpoints.sign_in))
+        .expect("federation endpoints are valid URLs");
+    {
+        let mut query = url.query_pairs_mut();
+        query.append_pair("Action", "login");
+        if let Some(issuer) = &options.issuer {
+            query.append_pair("Issuer", issuer);
+        }
+        query.append_pair("Destination", &destination);
+        query.append_pair("SigninToken", &token);
+    }
+    Ok(url.into())
+}
+
+/// The URL signing out of the console in the partition.
+pub fn logout_url(aws_partition: &str) -> Result<String> {
+    let endpoints = federation_endpoints(aws_partition)?;
+    Ok(format!("{}/oauth?Action=logout", endpoints.sign_in))
+}
+
+/// Open the URL in the default browser.
+pub fn open_in_browser(url: &str) -> Result<()> {
+    // `cmd /C start` would interpret the '&' in the URL
+    let mut command = if cfg!(target_os = "windows") {
+        let mut command = std::process::Command::new("rundll32");
+        command.arg("url.dll,FileProtocolHandler");
+        command
+    } else if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+    let status = command
+        .arg(url)
+        .status()
+        .map_err(ConsoleError::OpenBrowserFailed)?;
+    if !status.success() {
+        return Err(ConsoleError::OpenBrowserFailed(std::io::Error::other(format!(
+            "{status}"
+        ))));
+    }
+    Ok(())
+}
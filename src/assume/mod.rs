@@ -1,17 +1,49 @@
+pub mod console;
 pub mod models;
 mod azure;
 mod aws;
+mod retry;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
-use aws::acquire_aws_credentials;
+use aws::{acquire_aws_credentials, acquire_aws_credentials_with_web_identity};
 use models::TemporaryAwsCredentials;
 
+use crate::models::AssumeMethod;
 use crate::RoleInfo;
 
-pub async fn acquire_credentials(role_info: &RoleInfo) -> Result<TemporaryAwsCredentials> {
-    let saml_token = azure::saml_token(&role_info.account_id).await?;
-    let credentials = acquire_aws_credentials(role_info, &saml_token).await?;
-    Ok(credentials)
+/// Acquire credentials for the role. With `force`, cached Azure AD tokens and SAML assertions are
+/// not used either. Fails if this takes longer than the deadline in the network settings.
+pub async fn acquire_credentials(role_info: &RoleInfo, force: bool) -> Result<TemporaryAwsCredentials> {
+    let deadline = role_info.network.deadline;
+    tokio::time::timeout(deadline, acquire_credentials_without_deadline(role_info, force))
+        .await
+        .map_err(|_| anyhow!("Timed out acquiring credentials after {} seconds", deadline.as_secs()))?
 }
 
+async fn acquire_credentials_without_deadline(role_info: &RoleInfo, force: bool) -> Result<TemporaryAwsCredentials> {
+    // Rejected tokens and assertions are forgotten, so that the next run does not reuse them
+    let credentials = match role_info.assume_method {
+        AssumeMethod::Saml => {
+            let saml_token = azure::saml_token(role_info, force).await?;
+            acquire_aws_credentials(role_info, &saml_token)
+                .await
+                .inspect_err(|error| {
+                    if error.is_rejected_token() {
+                        azure::forget_saml_assertion(role_info);
+                    }
+                })?
+        }
+        AssumeMethod::WebIdentity => {
+            let oidc_token = azure::oidc_token_with_login(role_info, force).await?;
+            acquire_aws_credentials_with_web_identity(role_info, &oidc_token)
+                .await
+                .inspect_err(|error| {
+                    if error.is_rejected_token() {
+                        azure::forget_oidc_token(role_info);
+                    }
+                })?
+        }
+    };
+    Ok(credentials)
+}
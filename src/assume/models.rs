@@ -3,14 +3,16 @@ use std::path::{Path, PathBuf};
 use chrono::{DateTime, Local, Utc};
 use configparser::ini::{Ini, IniDefault, WriteOptions};
 use derive_builder::Builder;
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Deserializer, Serialize};
 
+use crate::private_files::{loose_permissions, write_private_file};
 use crate::{EnvVarsStyle, DEFAULT_REGION};
 
 pub type Result<T> = std::result::Result<T, ConfigError>;
 
 #[derive(Debug, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum ConfigError {
     #[error(transparent)]
     ConfigFilePathError(#[from] std::io::Error),
@@ -120,6 +122,12 @@ impl TemporaryAwsCredentialsBuilder {
 }
 
 impl TemporaryAwsCredentials {
+    /// Whether the credentials are still valid for at least `min_remaining`.
+    pub fn is_fresh(&self, min_remaining: std::time::Duration) -> bool {
+        chrono::Duration::from_std(min_remaining)
+            .is_ok_and(|min_remaining| self.expiration - min_remaining > Utc::now())
+    }
+
     pub fn output_as(&self, output_format: &OutputFormat) -> std::io::Result<()> {
         match output_format {
             OutputFormat::Json => {
@@ -132,7 +140,7 @@ impl TemporaryAwsCredentials {
                 profile,
             } => self
                 .to_credentials_file(config_file, credentials_file, profile)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)),
+                .map_err(std::io::Error::other),
             OutputFormat::EnvVars(style) => {
                 self.as_env_vars(style);
                 Ok(())
@@ -185,7 +193,16 @@ impl TemporaryAwsCredentials {
             .build();
 
         aws_config.pretty_write(aws_config_file_path, &write_options)?;
-        aws_credentials.pretty_write(aws_credentials_file_path, &write_options)?;
+        if let Some(mode) = loose_permissions(&aws_credentials_file_path)? {
+            warn!(
+                "{} can be accessed by other users (permissions {mode:o}). Restricting them to 600",
+                aws_credentials_file_path.display()
+            );
+        }
+        write_private_file(
+            &aws_credentials_file_path,
+            aws_credentials.pretty_writes(&write_options).as_bytes(),
+        )?;
 
         Ok(())
     }
@@ -197,10 +214,10 @@ impl TemporaryAwsCredentials {
                     r#"export AWS_ACCESS_KEY_ID={}
 export AWS_SECRET_ACCESS_KEY={}
 export AWS_SESSION_TOKEN={}
-export AWS_REGION={}
-export AWS_DEFAULT_REGION={DEFAULT_REGION}
+export AWS_REGION={region}
+export AWS_DEFAULT_REGION={region}
 "#,
-                    self.access_key_id, self.secret_access_key, self.session_token, self.region
+                    self.access_key_id, self.secret_access_key, self.session_token, region = self.region
                 )
             }
             EnvVarsStyle::PowerShell => {
@@ -208,15 +225,48 @@ export AWS_DEFAULT_REGION={DEFAULT_REGION}
                     r#"$env:AWS_ACCESS_KEY_ID="{}"
 $env:AWS_SECRET_ACCESS_KEY="{}"
 $env:AWS_SESSION_TOKEN="{}"
-$env:AWS_REGION="{}"
-$env:AWS_DEFAULT_REGION="{DEFAULT_REGION}""#,
-                    self.access_key_id, self.secret_access_key, self.session_token, self.region
+$env:AWS_REGION="{region}"
+$env:AWS_DEFAULT_REGION="{region}""#,
+                    self.access_key_id, self.secret_access_key, self.session_token, region = self.region
                 )
             }
         }
     }
 }
 
+/// An Azure AD access token together with the audience it was issued for and its expiration.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct OidcToken {
+    pub token: String,
+    pub audience: String,
+    pub expiration: DateTime<Utc>,
+}
+
+impl OidcToken {
+    /// Whether the token is still valid for at least `min_remaining`.
+    pub fn is_fresh(&self, min_remaining: chrono::Duration) -> bool {
+        self.expiration - min_remaining > Utc::now()
+    }
+}
+
+/// A SAML assertion from the token exchange. It is issued per account and carries all roles for
+/// that account, so it can be reused for assuming any of them until it expires.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct SamlAssertion {
+    pub assertion: String,
+    pub account_id: String,
+    pub not_on_or_after: DateTime<Utc>,
+}
+
+impl SamlAssertion {
+    /// Whether the assertion is still valid for at least `min_remaining`.
+    pub fn is_fresh(&self, min_remaining: chrono::Duration) -> bool {
+        self.not_on_or_after - min_remaining > Utc::now()
+    }
+}
+
 fn default_region() -> String {
     DEFAULT_REGION.to_string()
 }
@@ -228,7 +278,7 @@ fn create_if_not_exists(file_path: &Path) -> Result<()> {
         if let Some(parent) = file_path.parent() {
             std::fs::create_dir_all(parent)?;
         }
-        std::fs::File::create(file_path)?; // TODO: Set correct permissions on file. They should be 600 on Unix-y systems
+        write_private_file(file_path, b"")?;
     }
 
     Ok(())
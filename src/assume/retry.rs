@@ -0,0 +1,53 @@
+use std::collections::hash_map::RandomState;
+use std::fmt::Display;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use log::info;
+
+use crate::defaults::{RETRY_BASE_DELAY_MS, RETRY_MAX_DELAY_MS};
+
+/// Run `operation` until it succeeds, fails with an error that is not retryable, or
+/// `max_attempts` attempts have been made. Waits with exponential backoff and jitter between
+/// attempts.
+pub async fn with_retries<T, E, F, Fut>(
+    description: &str,
+    max_attempts: u32,
+    is_retryable: impl Fn(&E) -> bool,
+    mut operation: F,
+) -> std::result::Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = std::result::Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Err(error) if attempt < max_attempts && is_retryable(&error) => {
+                let delay = backoff(attempt);
+                info!(
+                    "{description} failed (attempt {attempt} of {max_attempts}): {error}. Retrying in {} ms",
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Exponential backoff with "equal jitter": half of the delay is fixed, the other half random.
+fn backoff(attempt: u32) -> Duration {
+    let exponential = RETRY_BASE_DELAY_MS
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(RETRY_MAX_DELAY_MS);
+    let half = exponential / 2;
+    Duration::from_millis(half + random() % (half + 1))
+}
+
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
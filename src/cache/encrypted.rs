@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex, OnceLock};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use inquire::Password;
+use log::{info, warn};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+
+use super::files::FileStore;
+use super::{CachedCredentialsError, CredentialStore, KeySource, Result};
+use crate::private_files::{loose_permissions, write_private_file};
+
+// Encrypted entries start with this, followed by the format version and the version's header.
+// Version 1: nonce, ciphertext with authentication tag. Encrypted with the key file.
+// Version 2: key source, for passphrases the PBKDF2 iterations and salt, nonce, ciphertext with
+// authentication tag. The header is authenticated along with the entry name.
+const MAGIC: &[u8] = b"aws-credentials-cli:encrypted:";
+const FORMAT_VERSION: u8 = 2;
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const KEY_FILE_SOURCE: u8 = 0;
+const PASSPHRASE_SOURCE: u8 = 1;
+const PBKDF2_ITERATIONS: u32 = 600_000;
+const PASSPHRASE_ENV_VAR: &str = "AWS_CREDENTIALS_CLI_CACHE_PASSPHRASE";
+const SALT_FILE: &str = "cache.salt";
+
+/// Whether the entry is in the encrypted format, as opposed to plain JSON.
+pub fn is_encrypted(contents: &[u8]) -> bool {
+    contents.starts_with(MAGIC)
+}
+
+fn default_key_file() -> Result<PathBuf> {
+    let path = dirs::config_dir()
+        .ok_or(CachedCredentialsError::UnsupportedPlatform)?
+        .join(env!("CARGO_PKG_NAME"))
+        .join("cache.key");
+    Ok(path)
+}
+
+fn random_bytes<const N: usize>() -> std::result::Result<[u8; N], String> {
+    let mut bytes = [0; N];
+    getrandom::getrandom(&mut bytes).map_err(|error| error.to_string())?;
+    Ok(bytes)
+}
+
+fn aead_key(key: &[u8; KEY_LEN]) -> LessSafeKey {
+    // Only fails for keys of the wrong length
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).expect("key of the right length"))
+}
+
+/// Read the base64 encoded bytes from the file, or create the file with random bytes if it does
+/// not exist.
+fn load_random_bytes<const N: usize>(path: &Path, description: &str) -> std::result::Result<[u8; N], String> {
+    let bytes = if path.exists() {
+        if let Some(mode) = loose_permissions(path).map_err(|error| error.to_string())? {
+            return Err(format!("the file can be accessed by other users (permissions {mode:o})"));
+        }
+        let encoded = std::fs::read_to_string(path).map_err(|error| error.to_string())?;
+        BASE64
+            .decode(encoded.trim())
+            .map_err(|error| format!("the {description} is not valid base64: {error}"))?
+    } else {
+        info!("Creating cache encryption {description} {}", path.display());
+        let bytes = random_bytes::<N>()?.to_vec();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|error| error.to_string())?;
+        }
+        write_private_file(path, format!("{}\n", BASE64.encode(&bytes)).as_bytes())
+            .map_err(|error| error.to_string())?;
+        bytes
+    };
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| format!("the {description} must be {N} bytes, but is {}", bytes.len()))
+}
+
+// Asked for once per process, as all caches of a run share it
+static PASSPHRASE: OnceLock<String> = OnceLock::new();
+
+/// The passphrase from the environment, or from a prompt if there is none.
+fn passphrase() -> std::result::Result<&'static str, String> {
+    if let Some(passphrase) = PASSPHRASE.get() {
+        return Ok(passphrase);
+    }
+    let passphrase = match std::env::var(PASSPHRASE_ENV_VAR) {
+        Ok(passphrase) => passphrase,
+        Err(_) => Password::new("Cache passphrase:")
+            .without_confirmation()
+            .with_help_message(&format!("Set {PASSPHRASE_ENV_VAR} to skip this prompt"))
+            .prompt()
+            .map_err(|error| format!("no passphrase in {PASSPHRASE_ENV_VAR} and prompting failed: {error}"))?,
+    };
+    if passphrase.is_empty() {
+        return Err(String::from("the passphrase is empty"));
+    }
+    Ok(PASSPHRASE.get_or_init(|| passphrase))
+}
+
+// Keys derived from passphrases by PBKDF2 iterations and salt. Kept for the whole process, so that
+// each key is derived only once however many stores and entries use it.
+type DerivedKeys = Mutex<HashMap<(&'static str, u32, [u8; SALT_LEN]), [u8; KEY_LEN]>>;
+
+static DERIVED_KEYS: LazyLock<DerivedKeys> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+enum Key {
+    File([u8; KEY_LEN]),
+    // New entries are encrypted with the key derived with the salt of the store. Entries keep
+    // their salt in the header, so that they can be read when the salt of the store changes.
+    Passphrase {
+        passphrase: &'static str,
+        salt: [u8; SALT_LEN],
+    },
+}
+
+impl Key {
+    fn derive(&self, iterations: u32, salt: [u8; SALT_LEN]) -> Option<[u8; KEY_LEN]> {
+        let Key::Passphrase { passphrase, .. } = self else {
+            return None;
+        };
+        let iterations = NonZeroU32::new(iterations)?;
+        let mut derived = DERIVED_KEYS.lock().unwrap();
+        let key = derived.entry((passphrase, iterations.get(), salt)).or_insert_with(|| {
+            let mut key = [0; KEY_LEN];
+            pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, passphrase.as_bytes(), &mut key);
+            key
+        });
+        Some(*key)
+    }
+}
+
+/// The parts of an encrypted entry.
+struct Envelope<'a> {
+    /// The authenticated header, empty for version 1.
+    header: &'a [u8],
+    key_source: u8,
+    iterations: u32,
+    salt: [u8; SALT_LEN],
+    nonce: [u8; NONCE_LEN],
+    ciphertext: &'a [u8],
+}
+
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if rest.len() < len {
+        return None;
+    }
+    let (taken, remaining) = rest.split_at(len);
+    *rest = remaining;
+    Some(taken)
+}
+
+fn parse(contents: &[u8]) -> Option<Envelope<'_>> {
+    let mut rest = contents.strip_prefix(MAGIC)?;
+    let version = take(&mut rest, 1)?[0];
+    let (key_source, iterations, salt) = match version {
+        1 => (KEY_FILE_SOURCE, 0, [0; SALT_LEN]),
+        2 => match take(&mut rest, 1)?[0] {
+            KEY_FILE_SOURCE => (KEY_FILE_SOURCE, 0, [0; SALT_LEN]),
+            PASSPHRASE_SOURCE => {
+                // The header is only authenticated after deriving the key, so a tampered iteration
+                // count must not make the derivation take forever
+                let iterations = u32::from_be_bytes(take(&mut rest, 4)?.try_into().ok()?);
+                if iterations != PBKDF2_ITERATIONS {
+                    return None;
+                }
+                (PASSPHRASE_SOURCE, iterations, take(&mut rest, SALT_LEN)?.try_into().ok()?)
+            }
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let nonce = take(&mut rest, NONCE_LEN)?.try_into().ok()?;
+    let header_len = contents.len() - rest.len();
+    Some(Envelope {
+        header: if version == 1 { &[] } else { &contents[..header_len] },
+        key_source,
+        iterations,
+        salt,
+        nonce,
+        ciphertext: rest,
+    })
+}
+
+/// Cache entries as files in the cache directory, encrypted with ChaCha20-Poly1305 using a key
+/// from a key file or derived from a passphrase. The entry name is authenticated as well, so
+/// entries can not be swapped. Plain JSON entries, e.g. from the file store, are read and
+/// encrypted.
+pub struct EncryptedStore {
+    files: FileStore,
+    key: Key,
+}
+
+impl EncryptedStore {
+    pub fn new(key_source: KeySource, key_file: Option<&Path>) -> Result<Self> {
+        let files = FileStore::new()?;
+        let key = match key_source {
+            KeySource::File => {
+                let key_file = match key_file {
+                    Some(key_file) => key_file.to_path_buf(),
+                    None => default_key_file()?,
+                };
+                let key = load_random_bytes(&key_file, "key").map_err(|message| CachedCredentialsError::EncryptionKeyError {
+                    origin: key_file.display().to_string(),
+                    message,
+                })?;
+                Key::File(key)
+            }
+            KeySource::Passphrase => {
+                let passphrase = passphrase().map_err(|message| CachedCredentialsError::EncryptionKeyError {
+                    origin: String::from("the passphrase"),
+                    message,
+                })?;
+                // Next to the entries, as it is only needed for them
+                let salt_file = files.path(SALT_FILE);
+                let salt = load_random_bytes(&salt_file, "salt").map_err(|message| {
+                    CachedCredentialsError::EncryptionKeyError {
+                        origin: salt_file.display().to_string(),
+                        message,
+                    }
+                })?;
+                Key::Passphrase { passphrase, salt }
+            }
+        };
+        Ok(Self { files, key })
+    }
+
+    fn encrypt(&self, name: &str, contents: &[u8]) -> Result<Vec<u8>> {
+        let encryption_failed = || CachedCredentialsError::EncryptionFailed(name.to_string());
+        let mut header = [MAGIC, &[FORMAT_VERSION]].concat();
+        let key = match &self.key {
+            Key::File(key) => {
+                header.push(KEY_FILE_SOURCE);
+                *key
+            }
+            Key::Passphrase { salt, .. } => {
+                header.push(PASSPHRASE_SOURCE);
+                header.extend_from_slice(&PBKDF2_ITERATIONS.to_be_bytes());
+                header.extend_from_slice(salt);
+                self.key.derive(PBKDF2_ITERATIONS, *salt).ok_or_else(encryption_failed)?
+            }
+        };
+        let nonce = random_bytes::<NONCE_LEN>().map_err(|_| encryption_failed())?;
+        header.extend_from_slice(&nonce);
+        let mut ciphertext = contents.to_vec();
+        aead_key(&key)
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from([&header, name.as_bytes()].concat()),
+                &mut ciphertext,
+            )
+            .map_err(|_| encryption_failed())?;
+        Ok([header, ciphertext].concat())
+    }
+
+    fn decrypt(&self, name: &str, contents: &[u8]) -> Result<Vec<u8>> {
+        let malformed = || CachedCredentialsError::DecryptionFailed(name.to_string());
+        let envelope = parse(contents).ok_or_else(malformed)?;
+        let key = match (&self.key, envelope.key_source) {
+            (Key::File(key), KEY_FILE_SOURCE) => *key,
+            (Key::Passphrase { .. }, PASSPHRASE_SOURCE) => self
+                .key
+                .derive(envelope.iterations, envelope.salt)
+                .ok_or_else(malformed)?,
+            _ => return Err(CachedCredentialsError::OtherKeySource(name.to_string())),
+        };
+        let mut ciphertext = envelope.ciphertext.to_vec();
+        let plaintext = aead_key(&key)
+            .open_in_place(
+                Nonce::assume_unique_for_key(envelope.nonce),
+                Aad::from([envelope.header, name.as_bytes()].concat()),
+                &mut ciphertext,
+            )
+            .map_err(|_| CachedCredentialsError::TamperedEntry(name.to_string()))?;
+        Ok(plaintext.to_vec())
+    }
+}
+
+impl CredentialStore for EncryptedStore {
+    fn load(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let Some(contents) = self.files.load(name)? else {
+            return Ok(None);
+        };
+        if is_encrypted(&contents) {
+            return self.decrypt(name, &contents).map(Some);
+        }
+        if !contents.starts_with(b"{") {
+            return Err(CachedCredentialsError::DecryptionFailed(name.to_string()));
+        }
+        // Written by the file store, e.g. before switching to this store
+        info!("Encrypting plain cache entry {name}");
+        // Files do not expire, so the expiration is not needed for storing
+        if let Err(error) = self.store(name, &contents, DateTime::<Utc>::MAX_UTC) {
+            warn!("Failed to encrypt plain cache entry {name}: {error}");
+        }
+        Ok(Some(contents))
+    }
+
+    fn store(&self, name: &str, contents: &[u8], expiration: DateTime<Utc>) -> Result<()> {
+        self.files.store(name, &self.encrypt(name, contents)?, expiration)
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        self.files.remove(name)
+    }
+
+    fn names(&self) -> Result<Vec<String>> {
+        self.files.names()
+    }
+
+    fn location(&self) -> String {
+        self.files.location()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: &str = "123456789012-deployer.creds";
+
+    fn store(directory: &tempfile::TempDir, key: Key) -> EncryptedStore {
+        EncryptedStore {
+            files: FileStore::in_directory(directory.path().to_path_buf()).unwrap(),
+            key,
+        }
+    }
+
+    fn expiration() -> DateTime<Utc> {
+        Utc::now() + chrono::Duration::hours(1)
+    }
+
+    #[test]
+    fn round_trips_entries() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = store(&directory, Key::File([7; KEY_LEN]));
+        store.store(NAME, b"{\"AccessKeyId\":\"AKIA\"}", expiration()).unwrap();
+        let contents = std::fs::read(directory.path().join(NAME)).unwrap();
+        assert!(is_encrypted(&contents));
+        assert_eq!(store.load(NAME).unwrap().unwrap(), b"{\"AccessKeyId\":\"AKIA\"}");
+    }
+
+    #[test]
+    fn rejects_tampered_and_swapped_entries() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = store(&directory, Key::File([7; KEY_LEN]));
+        let mut contents = store.encrypt(NAME, b"{}").unwrap();
+        assert!(matches!(
+            store.decrypt("123456789012-admin.creds", &contents),
+            Err(CachedCredentialsError::TamperedEntry(_))
+        ));
+        *contents.last_mut().unwrap() ^= 1;
+        assert!(matches!(store.decrypt(NAME, &contents), Err(CachedCredentialsError::TamperedEntry(_))));
+        let other_key = Key::File([8; KEY_LEN]);
+        let contents = store.encrypt(NAME, b"{}").unwrap();
+        assert!(matches!(
+            EncryptedStore { key: other_key, ..store }.decrypt(NAME, &contents),
+            Err(CachedCredentialsError::TamperedEntry(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_entries_of_other_key_sources() {
+        let directory = tempfile::tempdir().unwrap();
+        let file_store = store(&directory, Key::File([7; KEY_LEN]));
+        let contents = file_store.encrypt(NAME, b"{}").unwrap();
+        let passphrase_store = store(
+            &directory,
+            Key::Passphrase {
+                passphrase: "passphrase",
+                salt: [1; SALT_LEN],
+            },
+        );
+        assert!(matches!(
+            passphrase_store.decrypt(NAME, &contents),
+            Err(CachedCredentialsError::OtherKeySource(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_other_iteration_counts_without_deriving() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = store(
+            &directory,
+            Key::Passphrase {
+                passphrase: "rejects_other_iteration_counts_without_deriving",
+                salt: [4; SALT_LEN],
+            },
+        );
+        let header = [MAGIC, &[FORMAT_VERSION, PASSPHRASE_SOURCE], &u32::MAX.to_be_bytes(), &[4; SALT_LEN]].concat();
+        let contents = [&header[..], &[3; NONCE_LEN], &[0; 32]].concat();
+        assert!(matches!(store.decrypt(NAME, &contents), Err(CachedCredentialsError::DecryptionFailed(_))));
+        assert!(!DERIVED_KEYS
+            .lock()
+            .unwrap()
+            .keys()
+            .any(|(passphrase, ..)| *passphrase == "rejects_other_iteration_counts_without_deriving"));
+    }
+
+    #[test]
+    fn decrypts_version_1_entries() {
+        let directory = tempfile::tempdir().unwrap();
+        let key = [7; KEY_LEN];
+        let nonce = [3; NONCE_LEN];
+        // Version 1 authenticates the entry name only
+        let mut ciphertext = b"{}".to_vec();
+        aead_key(&key)
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(NAME), &mut ciphertext)
+            .unwrap();
+        let contents = [MAGIC, &[1], &nonce, &ciphertext].concat();
+        assert_eq!(store(&directory, Key::File(key)).decrypt(NAME, &contents).unwrap(), b"{}");
+        assert!(parse(&[MAGIC, &[9]].concat()).is_none());
+    }
+
+    #[test]
+    fn derives_passphrase_keys_once_per_salt() {
+        let directory = tempfile::tempdir().unwrap();
+        let salt = [2; SALT_LEN];
+        let store = store(
+            &directory,
+            Key::Passphrase {
+                passphrase: "derives_passphrase_keys_once_per_salt",
+                salt,
+            },
+        );
+        let first = store.encrypt(NAME, b"{}").unwrap();
+        let second = store.encrypt(NAME, b"{}").unwrap();
+        assert_eq!(store.decrypt(NAME, &first).unwrap(), b"{}");
+        assert_eq!(store.decrypt(NAME, &second).unwrap(), b"{}");
+        let derived = DERIVED_KEYS.lock().unwrap();
+        let keys = derived
+            .keys()
+            .filter(|(passphrase, ..)| *passphrase == "derives_passphrase_keys_once_per_salt")
+            .collect::<Vec<_>>();
+        assert_eq!(keys, [&("derives_passphrase_keys_once_per_salt", PBKDF2_ITERATIONS, salt)]);
+    }
+}
@@ -0,0 +1,94 @@
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+
+use super::{CachedCredentialsError, CredentialStore, CredentialsCache, Result};
+use crate::private_files::{create_private_dir, loose_permissions, restrict_permissions, write_private_file};
+
+// The extensions of the cache entries. Other files in the directory, e.g. locks, are not entries.
+const ENTRY_EXTENSIONS: [&str; 3] = ["creds", "token", "saml"];
+
+/// Cache entries as files in the cache directory, readable by the user only.
+pub struct FileStore {
+    directory: PathBuf,
+}
+
+impl FileStore {
+    pub fn new() -> Result<Self> {
+        Self::in_directory(CredentialsCache::directory()?)
+    }
+
+    pub fn in_directory(directory: PathBuf) -> Result<Self> {
+        create_private_dir(&directory)?;
+        Ok(Self { directory })
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.directory.join(name)
+    }
+}
+
+// Files written before cache files were private have the default permissions, usually 644, so the
+// user's own files are restricted. Files of other users may have been tampered with, so they are
+// not used.
+fn read_private_file(path: &Path) -> Result<Option<Vec<u8>>> {
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error.into()),
+    };
+    if let Some(mode) = loose_permissions(path)? {
+        if restrict_permissions(path).is_err() {
+            return Err(CachedCredentialsError::InsecurePermissions {
+                path: path.display().to_string(),
+                mode,
+            });
+        }
+        log::warn!(
+            "{} can be accessed by other users (permissions {mode:o}). Restricting them to 600",
+            path.display()
+        );
+    }
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    Ok(Some(contents))
+}
+
+impl CredentialStore for FileStore {
+    fn load(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        read_private_file(&self.path(name))
+    }
+
+    fn store(&self, name: &str, contents: &[u8], _expiration: DateTime<Utc>) -> Result<()> {
+        write_private_file(&self.path(name), contents)?;
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        match std::fs::remove_file(self.path(name)) {
+            Err(error) if error.kind() != ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn names(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            let is_entry = path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| ENTRY_EXTENSIONS.contains(&extension));
+            if let (true, Some(name)) = (is_entry, path.file_name().and_then(|name| name.to_str())) {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn location(&self) -> String {
+        self.directory.display().to_string()
+    }
+}
@@ -0,0 +1,166 @@
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::{CachedCredentialsError, CredentialsMetadata, Result};
+
+// Version 1: the bare credentials, token or SAML assertion. Credentials entries may have their
+// metadata in a `Metadata` field next to the credentials.
+// Version 2: an envelope with the format version, the expiration, the metadata and the entry.
+pub const FORMAT_VERSION: u64 = 2;
+
+/// A cache entry with what is needed to manage it, so that entries can be listed and pruned
+/// without reading the entry itself.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Envelope<T> {
+    pub format_version: u64,
+    pub expiration: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<CredentialsMetadata>,
+    pub entry: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(entry: T, expiration: DateTime<Utc>, metadata: Option<CredentialsMetadata>) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            expiration,
+            metadata,
+            entry,
+        }
+    }
+}
+
+type Migration = fn(Map<String, Value>) -> Option<Map<String, Value>>;
+
+// The migration at index `i` upgrades entries from version `i + 1` to the next version. Changes to
+// the entries add a version and a migration, so that existing entries stay readable.
+const MIGRATIONS: [Migration; (FORMAT_VERSION - 1) as usize] = [wrap_bare_entry];
+
+fn wrap_bare_entry(mut entry: Map<String, Value>) -> Option<Map<String, Value>> {
+    // SAML assertions expire at `NotOnOrAfter`
+    let expiration = entry.get("Expiration").or_else(|| entry.get("NotOnOrAfter"))?.clone();
+    let mut envelope = Map::new();
+    envelope.insert(String::from("FormatVersion"), Value::from(2));
+    envelope.insert(String::from("Expiration"), expiration);
+    if let Some(metadata) = entry.remove("Metadata") {
+        envelope.insert(String::from("Metadata"), metadata);
+    }
+    envelope.insert(String::from("Entry"), Value::Object(entry));
+    Some(envelope)
+}
+
+/// Parse an entry of the current or an older format version, upgrading older ones. Returns
+/// whether the entry was upgraded along with it.
+pub fn parse<T: DeserializeOwned>(name: &str, contents: &[u8]) -> Result<(Envelope<T>, bool)> {
+    let malformed = || CachedCredentialsError::MalformedEntry(name.to_string());
+    let mut entry: Map<String, Value> = serde_json::from_slice(contents)?;
+    let version = match entry.get("FormatVersion") {
+        Some(version) => version.as_u64().filter(|version| *version > 0).ok_or_else(malformed)?,
+        None => 1,
+    };
+    if version > FORMAT_VERSION {
+        return Err(CachedCredentialsError::UnsupportedFormat {
+            name: name.to_string(),
+            version,
+        });
+    }
+    for migration in &MIGRATIONS[(version - 1) as usize..] {
+        entry = migration(entry).ok_or_else(malformed)?;
+    }
+    Ok((serde_json::from_value(Value::Object(entry))?, version < FORMAT_VERSION))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::SubsecRound;
+
+    use super::*;
+
+    use crate::assume::models::{OidcToken, SamlAssertion, TemporaryAwsCredentials};
+
+    fn expiration() -> DateTime<Utc> {
+        (Utc::now() + chrono::Duration::hours(1)).trunc_subsecs(0)
+    }
+
+    #[test]
+    fn upgrades_bare_credentials() {
+        let expiration = expiration();
+        let contents = format!(
+            r#"{{"Version":1,"AccessKeyId":"AKIA","SecretAccessKey":"secret","SessionToken":"token","Expiration":"{}"}}"#,
+            expiration.to_rfc3339()
+        );
+        let (envelope, upgraded) = parse::<TemporaryAwsCredentials>("entry.creds", contents.as_bytes()).unwrap();
+        assert!(upgraded);
+        assert_eq!(envelope.format_version, FORMAT_VERSION);
+        assert_eq!(envelope.expiration, expiration);
+        assert!(envelope.metadata.is_none());
+        assert_eq!(envelope.entry.access_key_id, "AKIA");
+    }
+
+    #[test]
+    fn upgrades_credentials_with_metadata() {
+        let contents = format!(
+            r#"{{"Version":1,"AccessKeyId":"AKIA","SecretAccessKey":"secret","SessionToken":"token","Expiration":"{}",
+            "Metadata":{{"AwsPartition":"aws","AccountId":"123456789012","RoleName":"deployer","Region":"eu-west-1",
+            "TenantId":null,"AzureUsername":null,"AssumeMethod":"saml","StsEndpointUrl":null,"AcquiredAt":null}}}}"#,
+            expiration().to_rfc3339()
+        );
+        let (envelope, upgraded) = parse::<TemporaryAwsCredentials>("entry.creds", contents.as_bytes()).unwrap();
+        assert!(upgraded);
+        assert_eq!(envelope.metadata.unwrap().account_id, "123456789012");
+        assert_eq!(envelope.entry.session_token, "token");
+    }
+
+    #[test]
+    fn upgrades_bare_token() {
+        let expiration = expiration();
+        let contents = format!(
+            r#"{{"Token":"eyJ0","Audience":"audience","Expiration":"{}"}}"#,
+            expiration.to_rfc3339()
+        );
+        let (envelope, upgraded) = parse::<OidcToken>("audience.token", contents.as_bytes()).unwrap();
+        assert!(upgraded);
+        assert_eq!(envelope.expiration, expiration);
+        assert_eq!(envelope.entry.token, "eyJ0");
+    }
+
+    #[test]
+    fn upgrades_bare_saml_assertion() {
+        let expiration = expiration();
+        let contents = format!(
+            r#"{{"Assertion":"PHNhbWw+","AccountId":"123456789012","NotOnOrAfter":"{}"}}"#,
+            expiration.to_rfc3339()
+        );
+        let (envelope, upgraded) = parse::<SamlAssertion>("123456789012.saml", contents.as_bytes()).unwrap();
+        assert!(upgraded);
+        assert_eq!(envelope.expiration, expiration);
+        assert_eq!(envelope.entry.not_on_or_after, expiration);
+    }
+
+    #[test]
+    fn reads_current_format_without_upgrading() {
+        let expiration = expiration();
+        let token = OidcToken {
+            token: String::from("eyJ0"),
+            audience: String::from("audience"),
+            expiration,
+        };
+        let contents = serde_json::to_vec(&Envelope::new(&token, expiration, None)).unwrap();
+        let (envelope, upgraded) = parse::<OidcToken>("audience.token", &contents).unwrap();
+        assert!(!upgraded);
+        assert_eq!(envelope.entry.token, "eyJ0");
+    }
+
+    #[test]
+    fn rejects_newer_and_invalid_versions() {
+        let newer = parse::<serde_json::Value>("entry.creds", br#"{"FormatVersion":99}"#);
+        assert!(matches!(newer, Err(CachedCredentialsError::UnsupportedFormat { version: 99, .. })));
+        let invalid = parse::<serde_json::Value>("entry.creds", br#"{"FormatVersion":0}"#);
+        assert!(matches!(invalid, Err(CachedCredentialsError::MalformedEntry(_))));
+        let no_expiration = parse::<serde_json::Value>("entry.creds", br#"{"AccessKeyId":"AKIA"}"#);
+        assert!(matches!(no_expiration, Err(CachedCredentialsError::MalformedEntry(_))));
+    }
+}
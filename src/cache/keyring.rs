@@ -0,0 +1,309 @@
+use chrono::{DateTime, Utc};
+
+use super::{CachedCredentialsError, CredentialStore, KeyringKind, Result};
+
+// Entries are user keys with descriptions like `aws-credentials-cli:123456789012-deployer.creds`
+#[cfg(target_os = "linux")]
+fn key_description(name: &str) -> String {
+    format!("{}:{name}", env!("CARGO_PKG_NAME"))
+}
+
+#[cfg(target_os = "linux")]
+mod keyctl {
+    use std::ffi::CString;
+    use std::io;
+
+    use libc::{c_long, syscall, SYS_add_key, SYS_keyctl};
+
+    pub const KEY_SPEC_SESSION_KEYRING: i32 = -3;
+    pub const KEY_SPEC_USER_KEYRING: i32 = -4;
+    const KEYCTL_GET_KEYRING_ID: c_long = 0;
+    const KEYCTL_SETPERM: c_long = 5;
+    const KEYCTL_DESCRIBE: c_long = 6;
+    const KEYCTL_UNLINK: c_long = 9;
+    const KEYCTL_SEARCH: c_long = 10;
+    const KEYCTL_READ: c_long = 11;
+    const KEYCTL_SET_TIMEOUT: c_long = 15;
+    // All permissions for processes possessing the key and for the user, none for others. Lets
+    // sessions that do not possess the user keyring, e.g. over SSH, use the entries.
+    const KEY_PERMISSIONS: c_long = 0x3f3f_0000;
+    const USER_KEY_TYPE: &str = "user";
+
+    fn check(result: c_long) -> io::Result<c_long> {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result)
+        }
+    }
+
+    fn c_string(value: &str) -> io::Result<CString> {
+        CString::new(value).map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))
+    }
+
+    // Errors for keys that do not exist, or have expired or been revoked
+    fn is_missing_key(error: &io::Error) -> bool {
+        matches!(error.raw_os_error(), Some(libc::ENOKEY | libc::EKEYEXPIRED | libc::EKEYREVOKED))
+    }
+
+    /// The serial number of the keyring, creating it if needed.
+    pub fn keyring_id(keyring: i32) -> io::Result<i32> {
+        // SAFETY: KEYCTL_GET_KEYRING_ID only takes integer arguments and no pointers.
+        let id = check(unsafe { syscall(SYS_keyctl, KEYCTL_GET_KEYRING_ID, keyring as c_long, 1 as c_long) })?;
+        Ok(id as i32)
+    }
+
+    pub fn search(keyring: i32, description: &str) -> io::Result<Option<i32>> {
+        let key_type = c_string(USER_KEY_TYPE)?;
+        let description = c_string(description)?;
+        // SAFETY: Both pointers are to NUL-terminated strings that outlive the call, and the kernel
+        // only reads them.
+        let result = unsafe {
+            syscall(
+                SYS_keyctl,
+                KEYCTL_SEARCH,
+                keyring as c_long,
+                key_type.as_ptr(),
+                description.as_ptr(),
+                0 as c_long,
+            )
+        };
+        match check(result) {
+            Ok(key) => Ok(Some(key as i32)),
+            Err(error) if is_missing_key(&error) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    // Reads with a buffer that is too small return the needed size, so read until it fits
+    fn read_with_retry(key: i32) -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        loop {
+            // SAFETY: The kernel writes at most `buffer.len()` bytes to the buffer, which is
+            // allocated for that many. It writes nothing for an empty buffer, whose pointer is
+            // dangling.
+            let size = check(unsafe {
+                syscall(
+                    SYS_keyctl,
+                    KEYCTL_READ,
+                    key as c_long,
+                    buffer.as_mut_ptr(),
+                    buffer.len() as c_long,
+                )
+            })? as usize;
+            if size <= buffer.len() {
+                buffer.truncate(size);
+                return Ok(buffer);
+            }
+            buffer.resize(size, 0);
+        }
+    }
+
+    pub fn read(key: i32) -> io::Result<Option<Vec<u8>>> {
+        match read_with_retry(key) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(error) if is_missing_key(&error) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn add(keyring: i32, description: &str, contents: &[u8], timeout: u32) -> io::Result<()> {
+        let key_type = c_string(USER_KEY_TYPE)?;
+        let description = c_string(description)?;
+        // SAFETY: The type and description are NUL-terminated strings, and the payload pointer is
+        // valid for `contents.len()` bytes. All of them outlive the call, and the kernel only reads
+        // them.
+        let key = check(unsafe {
+            syscall(
+                SYS_add_key,
+                key_type.as_ptr(),
+                description.as_ptr(),
+                contents.as_ptr(),
+                contents.len(),
+                keyring as c_long,
+            )
+        })?;
+        // SAFETY: KEYCTL_SETPERM only takes integer arguments and no pointers.
+        check(unsafe { syscall(SYS_keyctl, KEYCTL_SETPERM, key, KEY_PERMISSIONS) })?;
+        // SAFETY: KEYCTL_SET_TIMEOUT only takes integer arguments and no pointers.
+        check(unsafe { syscall(SYS_keyctl, KEYCTL_SET_TIMEOUT, key, timeout as c_long) })?;
+        Ok(())
+    }
+
+    pub fn unlink(keyring: i32, key: i32) -> io::Result<()> {
+        // SAFETY: KEYCTL_UNLINK only takes integer arguments and no pointers.
+        match check(unsafe { syscall(SYS_keyctl, KEYCTL_UNLINK, key as c_long, keyring as c_long) }) {
+            Err(error) if !is_missing_key(&error) => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    /// The keys linked to the keyring with their descriptions, for the user keys only.
+    pub fn list(keyring: i32) -> io::Result<Vec<(i32, String)>> {
+        let keys: Vec<i32> = read_with_retry(keyring)?
+            .chunks_exact(4)
+            .map(|serial| i32::from_ne_bytes([serial[0], serial[1], serial[2], serial[3]]))
+            .collect();
+        let mut user_keys = Vec::new();
+        for key in keys {
+            // Described as `type;uid;gid;permissions;description`
+            let description = match describe(key) {
+                Ok(description) => description,
+                Err(error) if is_missing_key(&error) => continue,
+                Err(error) => return Err(error),
+            };
+            let mut parts = description.splitn(5, ';');
+            if parts.next() == Some(USER_KEY_TYPE) {
+                if let Some(description) = parts.nth(3) {
+                    user_keys.push((key, description.to_string()));
+                }
+            }
+        }
+        Ok(user_keys)
+    }
+
+    fn describe(key: i32) -> io::Result<String> {
+        let mut buffer = vec![0u8; 256];
+        loop {
+            // SAFETY: The kernel writes at most `buffer.len()` bytes to the buffer, which is
+            // allocated for that many.
+            let size = check(unsafe {
+                syscall(
+                    SYS_keyctl,
+                    KEYCTL_DESCRIBE,
+                    key as c_long,
+                    buffer.as_mut_ptr(),
+                    buffer.len() as c_long,
+                )
+            })? as usize;
+            if size <= buffer.len() {
+                // The size includes the terminating NUL
+                buffer.truncate(size.saturating_sub(1));
+                return Ok(String::from_utf8_lossy(&buffer).to_string());
+            }
+            buffer.resize(size, 0);
+        }
+    }
+}
+
+/// Cache entries as user keys in a Linux kernel keyring. They never touch the disk, and the
+/// kernel removes them when they expire.
+pub struct KeyringStore {
+    #[cfg(target_os = "linux")]
+    keyring: i32,
+    #[cfg(target_os = "linux")]
+    kind: KeyringKind,
+}
+
+#[cfg(target_os = "linux")]
+impl KeyringStore {
+    pub fn new(kind: KeyringKind) -> Result<Self> {
+        let keyring = match kind {
+            KeyringKind::User => keyctl::KEY_SPEC_USER_KEYRING,
+            KeyringKind::Session => keyctl::KEY_SPEC_SESSION_KEYRING,
+        };
+        let keyring = keyctl::keyring_id(keyring).map_err(CachedCredentialsError::KeyringError)?;
+        Ok(Self { keyring, kind })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl CredentialStore for KeyringStore {
+    fn load(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let read = || match keyctl::search(self.keyring, &key_description(name))? {
+            Some(key) => keyctl::read(key),
+            None => Ok(None),
+        };
+        read().map_err(CachedCredentialsError::KeyringError)
+    }
+
+    fn store(&self, name: &str, contents: &[u8], expiration: DateTime<Utc>) -> Result<()> {
+        // A timeout of 0 would mean no timeout at all
+        let timeout = (expiration - Utc::now()).num_seconds().clamp(1, i64::from(u32::MAX)) as u32;
+        keyctl::add(self.keyring, &key_description(name), contents, timeout)
+            .map_err(CachedCredentialsError::KeyringError)
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        let remove = || match keyctl::search(self.keyring, &key_description(name))? {
+            Some(key) => keyctl::unlink(self.keyring, key),
+            None => Ok(()),
+        };
+        remove().map_err(CachedCredentialsError::KeyringError)
+    }
+
+    fn names(&self) -> Result<Vec<String>> {
+        let prefix = key_description("");
+        let mut names: Vec<String> = keyctl::list(self.keyring)
+            .map_err(CachedCredentialsError::KeyringError)?
+            .into_iter()
+            .filter_map(|(_, description)| description.strip_prefix(&prefix).map(str::to_string))
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn location(&self) -> String {
+        match self.kind {
+            KeyringKind::User => String::from("the user keyring"),
+            KeyringKind::Session => String::from("the session keyring"),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl KeyringStore {
+    pub fn new(_kind: KeyringKind) -> Result<Self> {
+        Err(CachedCredentialsError::UnsupportedPlatform)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl CredentialStore for KeyringStore {
+    fn load(&self, _name: &str) -> Result<Option<Vec<u8>>> {
+        Err(CachedCredentialsError::UnsupportedPlatform)
+    }
+
+    fn store(&self, _name: &str, _contents: &[u8], _expiration: DateTime<Utc>) -> Result<()> {
+        Err(CachedCredentialsError::UnsupportedPlatform)
+    }
+
+    fn remove(&self, _name: &str) -> Result<()> {
+        Err(CachedCredentialsError::UnsupportedPlatform)
+    }
+
+    fn names(&self) -> Result<Vec<String>> {
+        Err(CachedCredentialsError::UnsupportedPlatform)
+    }
+
+    fn location(&self) -> String {
+        String::from("the kernel keyring")
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_entries_in_the_session_keyring() {
+        // Containers and sandboxes often do not allow keyctl
+        let Ok(store) = KeyringStore::new(KeyringKind::Session) else {
+            eprintln!("Skipping, the session keyring is not available");
+            return;
+        };
+        let name = format!("test-{}.creds", std::process::id());
+        let expiration = Utc::now() + chrono::Duration::minutes(5);
+        if let Err(error) = store.store(&name, b"contents", expiration) {
+            eprintln!("Skipping, can not add keys to the session keyring: {error}");
+            return;
+        }
+        assert_eq!(store.load(&name).unwrap().as_deref(), Some(&b"contents"[..]));
+        assert!(store.names().unwrap().contains(&name));
+
+        store.remove(&name).unwrap();
+        assert_eq!(store.load(&name).unwrap(), None);
+        assert!(!store.names().unwrap().contains(&name));
+        store.remove(&name).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Local, Utc};
+use serde::de::IgnoredAny;
+use serde::Serialize;
+
+use super::format::{self, Envelope};
+use super::{encrypted, CachedCredentialsError, CredentialStore, Result};
+
+/// A credentials entry as shown by `cache list`.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct CredentialsListing {
+    pub name: String,
+    pub account_id: Option<String>,
+    pub account_alias: Option<String>,
+    pub role_name: Option<String>,
+    pub aws_partition: Option<String>,
+    pub region: Option<String>,
+    pub acquired_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub remaining_seconds: Option<i64>,
+    pub expired: Option<bool>,
+    /// Why the entry could not be read, if it could not.
+    pub error: Option<String>,
+}
+
+/// The envelope of the entry, without reading the entry itself. Unlike for using the entry,
+/// expired entries are read as well, and entries in older formats are not written back.
+pub(super) fn read_summary(store: &dyn CredentialStore, name: &str) -> Result<Envelope<IgnoredAny>> {
+    let contents = store
+        .load(name)?
+        .ok_or_else(|| CachedCredentialsError::NotFound(name.to_string()))?;
+    if encrypted::is_encrypted(&contents) {
+        return Err(CachedCredentialsError::EncryptedEntry(name.to_string()));
+    }
+    Ok(format::parse(name, &contents)?.0)
+}
+
+// Entries from before metadata was stored are named `{account}-{role}[-{tenant}][-{username}]`
+fn account_and_role_from_name(name: &str) -> (Option<String>, Option<String>) {
+    let stem = name.strip_suffix(".creds").unwrap_or(name);
+    match stem.split_once('-') {
+        Some((account_id, role_name)) => (Some(account_id.to_string()), Some(role_name.replace("%2F", "/"))),
+        None => (None, None),
+    }
+}
+
+/// The credentials entries of the store, with the account aliases from `aliases`.
+pub fn list_credentials(store: &dyn CredentialStore, aliases: &HashMap<String, String>) -> Result<Vec<CredentialsListing>> {
+    let now = Utc::now();
+    let mut listings = Vec::new();
+    for name in store.names()?.into_iter().filter(|name| name.ends_with(".creds")) {
+        let (account_id, role_name) = account_and_role_from_name(&name);
+        let mut listing = CredentialsListing {
+            name: name.clone(),
+            account_id,
+            account_alias: None,
+            role_name,
+            aws_partition: None,
+            region: None,
+            acquired_at: None,
+            expires_at: None,
+            remaining_seconds: None,
+            expired: None,
+            error: None,
+        };
+        match read_summary(store, &name) {
+            Ok(summary) => {
+                if let Some(metadata) = summary.metadata {
+                    listing.account_id = Some(metadata.account_id);
+                    listing.role_name = Some(metadata.role_name);
+                    listing.aws_partition = Some(metadata.aws_partition);
+                    listing.region = Some(metadata.region);
+                    listing.acquired_at = metadata.acquired_at;
+                }
+                listing.expires_at = Some(summary.expiration);
+                listing.remaining_seconds = Some((summary.expiration - now).num_seconds().max(0));
+                listing.expired = Some(summary.expiration <= now);
+            }
+            Err(error) => listing.error = Some(error.to_string()),
+        }
+        listing.account_alias = listing
+            .account_id
+            .as_ref()
+            .and_then(|account_id| aliases.get(account_id))
+            .cloned();
+        listings.push(listing);
+    }
+    Ok(listings)
+}
+
+fn local_time(time: &Option<DateTime<Utc>>) -> String {
+    time.map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| String::from("-"))
+}
+
+fn remaining(listing: &CredentialsListing) -> String {
+    match (listing.expired, listing.remaining_seconds) {
+        (Some(true), _) => String::from("expired"),
+        (_, Some(seconds)) => format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60),
+        _ => String::from("-"),
+    }
+}
+
+/// Print the listings as a table, with unreadable entries and their errors after it. The table is
+/// left out if no entry can be read.
+pub fn print_table(listings: &[CredentialsListing]) {
+    if listings.is_empty() {
+        println!("No cached credentials");
+        return;
+    }
+    if listings.iter().any(|listing| listing.error.is_none()) {
+        print_readable(listings);
+    }
+    for listing in listings {
+        if let Some(error) = &listing.error {
+            println!("Unreadable entry {}: {error}", listing.name);
+        }
+    }
+}
+
+fn print_readable(listings: &[CredentialsListing]) {
+    let text = |value: &Option<String>| value.clone().unwrap_or_else(|| String::from("-"));
+    let header = ["ACCOUNT", "ROLE", "PARTITION", "REGION", "ACQUIRED", "EXPIRES", "REMAINING", "EXPIRED"].map(String::from);
+    let mut rows = vec![header];
+    for listing in listings.iter().filter(|listing| listing.error.is_none()) {
+        let account = match (&listing.account_id, &listing.account_alias) {
+            (Some(account_id), Some(alias)) => format!("{account_id} ({alias})"),
+            (account_id, _) => text(account_id),
+        };
+        rows.push([
+            account,
+            text(&listing.role_name),
+            text(&listing.aws_partition),
+            text(&listing.region),
+            local_time(&listing.acquired_at),
+            local_time(&listing.expires_at),
+            remaining(listing),
+            match listing.expired {
+                Some(true) => String::from("yes"),
+                Some(false) => String::from("no"),
+                None => String::from("-"),
+            },
+        ]);
+    }
+    let widths: Vec<usize> = (0..rows[0].len())
+        .map(|column| rows.iter().map(|row| row[column].chars().count()).max().unwrap_or(0))
+        .collect();
+    for row in &rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(value, width)| format!("{value:width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
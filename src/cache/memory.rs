@@ -0,0 +1,39 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+use chrono::{DateTime, Utc};
+
+use super::{CredentialStore, Result};
+
+// Shared by all caches of the process, so that e.g. `serve` reuses the token for refreshing
+static ENTRIES: LazyLock<Mutex<HashMap<String, Vec<u8>>>> = LazyLock::new(Default::default);
+
+/// Cache entries in the memory of the running process. Nothing is written to disk, and the
+/// entries are gone when the process exits.
+pub struct MemoryStore;
+
+impl CredentialStore for MemoryStore {
+    fn load(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        Ok(ENTRIES.lock().unwrap().get(name).cloned())
+    }
+
+    fn store(&self, name: &str, contents: &[u8], _expiration: DateTime<Utc>) -> Result<()> {
+        ENTRIES.lock().unwrap().insert(name.to_string(), contents.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        ENTRIES.lock().unwrap().remove(name);
+        Ok(())
+    }
+
+    fn names(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = ENTRIES.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        Ok(names)
+    }
+
+    fn location(&self) -> String {
+        String::from("memory")
+    }
+}
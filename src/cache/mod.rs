@@ -0,0 +1,584 @@
+mod encrypted;
+mod files;
+mod format;
+mod keyring;
+mod list;
+mod memory;
+mod removal;
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::assume::models::{OidcToken, SamlAssertion, TemporaryAwsCredentials};
+use super::models::{AzureAccount, RoleInfo};
+use super::private_files::create_private_dir;
+use format::Envelope;
+
+pub use list::{list_credentials, print_table};
+pub use removal::{EntryFilter, Removal};
+
+pub type Result<T> = std::result::Result<T, CachedCredentialsError>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CachedCredentialsError {
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+    #[error(transparent)]
+    FileSystemError(#[from] std::io::Error),
+    #[error("Unsupported platform")]
+    UnsupportedPlatform,
+    #[error("{path} can be accessed by other users (permissions {mode:o})")]
+    InsecurePermissions { path: String, mode: u32 },
+    #[error("No cached entry {0}")]
+    NotFound(String),
+    #[error("Keyring operation failed: {0}")]
+    KeyringError(std::io::Error),
+    #[error("Failed to load the cache encryption key from {origin}: {message}")]
+    EncryptionKeyError { origin: String, message: String },
+    #[error("Failed to encrypt cache entry {0}")]
+    EncryptionFailed(String),
+    #[error("Failed to decrypt cache entry {0}: unknown format")]
+    DecryptionFailed(String),
+    #[error("Cache entry {0} failed authentication. It was modified or encrypted with another key")]
+    TamperedEntry(String),
+    #[error("Cache entry {0} is encrypted with another key source")]
+    OtherKeySource(String),
+    #[error("Cache entry {0} is encrypted. Select the 'encrypted' cache store to read it")]
+    EncryptedEntry(String),
+    #[error("Cache entry {0} is not in a known format")]
+    MalformedEntry(String),
+    #[error("Cache entry {name} has format version {version}, which is newer than this version supports")]
+    UnsupportedFormat { name: String, version: u64 },
+    #[error("Cached credentials expired at {0}")]
+    Expired(DateTime<Utc>),
+}
+
+/// The kind of store that cached credentials and tokens are kept in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StoreKind {
+    /// JSON files in the cache directory.
+    #[default]
+    Files,
+    /// Memory of the running process only, so nothing is kept after it exits.
+    Memory,
+    /// Encrypted files in the cache directory.
+    Encrypted,
+    /// The Linux kernel keyring.
+    Keyring,
+}
+
+impl FromStr for StoreKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "files" => Ok(Self::Files),
+            "memory" => Ok(Self::Memory),
+            "encrypted" => Ok(Self::Encrypted),
+            "keyring" => Ok(Self::Keyring),
+            _ => Err(format!("unknown cache store '{value}'")),
+        }
+    }
+}
+
+/// The kernel keyring that the keyring store keeps entries in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyringKind {
+    /// Shared by all sessions of the user, and kept until the user's last session ends.
+    #[default]
+    User,
+    /// Only visible to the current login session.
+    Session,
+}
+
+impl FromStr for KeyringKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "user" => Ok(Self::User),
+            "session" => Ok(Self::Session),
+            _ => Err(format!("unknown keyring '{value}'")),
+        }
+    }
+}
+
+/// Where the encrypted store gets its key from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeySource {
+    /// A random key in a key file.
+    #[default]
+    File,
+    /// A key derived from a passphrase.
+    Passphrase,
+}
+
+impl FromStr for KeySource {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "file" => Ok(Self::File),
+            "passphrase" => Ok(Self::Passphrase),
+            _ => Err(format!("unknown key source '{value}'")),
+        }
+    }
+}
+
+/// The `[cache]` settings of the config file.
+#[derive(Clone, Debug, Default)]
+pub struct CacheSettings {
+    pub store: StoreKind,
+    pub keyring: KeyringKind,
+    pub key_source: KeySource,
+    /// The key for the encrypted store. It is created if it does not exist.
+    pub key_file: Option<PathBuf>,
+}
+
+/// A place to keep cache entries. Entries are named like the files of the file store, e.g.
+/// `123456789012-deployer.creds`.
+pub trait CredentialStore: Send + Sync {
+    /// The contents of the entry, or `None` if there is no such entry.
+    fn load(&self, name: &str) -> Result<Option<Vec<u8>>>;
+    /// Add or replace the entry. Stores that can expire entries drop it after `expiration`.
+    fn store(&self, name: &str, contents: &[u8], expiration: DateTime<Utc>) -> Result<()>;
+    /// Remove the entry. Removing a missing entry is not an error.
+    fn remove(&self, name: &str) -> Result<()>;
+    /// The names of all entries.
+    fn names(&self) -> Result<Vec<String>>;
+    /// Where the entries are kept, for showing to the user.
+    fn location(&self) -> String;
+}
+
+/// Open the store selected in the settings.
+pub fn open_store(settings: &CacheSettings) -> Result<Box<dyn CredentialStore>> {
+    let store: Box<dyn CredentialStore> = match settings.store {
+        StoreKind::Files => Box::new(files::FileStore::new()?),
+        StoreKind::Memory => Box::new(memory::MemoryStore),
+        StoreKind::Encrypted => Box::new(encrypted::EncryptedStore::new(settings.key_source, settings.key_file.as_deref())?),
+        StoreKind::Keyring => Box::new(keyring::KeyringStore::new(settings.keyring)?),
+    };
+    Ok(store)
+}
+
+// Credentials and tokens acquired through different Azure AD tenants or users must not overwrite
+// each other
+fn azure_account_suffix(azure_account: &AzureAccount) -> String {
+    [&azure_account.tenant_id, &azure_account.username]
+        .into_iter()
+        .flatten()
+        .map(|part| format!("-{part}"))
+        .collect()
+}
+
+// Role names may include an IAM path. '%' is not allowed in role names, so the encoding is
+// unambiguous.
+fn file_name_component(value: &str) -> String {
+    value.replace('/', "%2F")
+}
+
+// Entries in older formats are written back in the current one, so that they are upgraded once
+fn load_entry<T: DeserializeOwned + Serialize>(store: &dyn CredentialStore, name: &str) -> Result<Envelope<T>> {
+    let contents = store
+        .load(name)?
+        .ok_or_else(|| CachedCredentialsError::NotFound(name.to_string()))?;
+    if encrypted::is_encrypted(&contents) {
+        return Err(CachedCredentialsError::EncryptedEntry(name.to_string()));
+    }
+    let (envelope, upgraded) = format::parse(name, &contents)?;
+    if upgraded {
+        log::info!("Upgrading cache entry {name} to format version {}", format::FORMAT_VERSION);
+        if let Err(error) = store_entry(store, name, &envelope) {
+            log::warn!("Failed to upgrade cache entry {name}: {error}");
+        }
+    }
+    Ok(envelope)
+}
+
+fn store_entry<T: Serialize>(store: &dyn CredentialStore, name: &str, envelope: &Envelope<T>) -> Result<()> {
+    store.store(name, &serde_json::to_vec_pretty(envelope)?, envelope.expiration)
+}
+
+// How often to check whether another process released the lock on a cache entry
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An advisory lock on a cache entry. It is released when dropped, or by the operating system
+/// when the holding process exits, so locks of crashed processes do not need cleaning up.
+#[derive(Debug)]
+pub struct CacheLock {
+    _file: Option<File>,
+}
+
+// Whether the open file is still the one at the path. A lock on a file that has since been removed
+// does not keep other processes from locking a new file at the path.
+#[cfg(unix)]
+fn is_current_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(open), Ok(current)) => open.dev() == current.dev() && open.ino() == current.ino(),
+        _ => false,
+    }
+}
+
+// Open files can not be removed
+#[cfg(not(unix))]
+fn is_current_file(_file: &File, _path: &Path) -> bool {
+    true
+}
+
+/// What cached credentials were acquired for. Stored with the credentials, so that entries can be
+/// listed without knowing how their names were derived.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct CredentialsMetadata {
+    pub aws_partition: String,
+    pub account_id: String,
+    pub role_name: String,
+    /// The region the credentials were last requested for. They are valid in every region of the
+    /// partition.
+    pub region: String,
+    pub tenant_id: Option<String>,
+    pub azure_username: Option<String>,
+    pub assume_method: String,
+    pub sts_endpoint_url: Option<String>,
+    /// Unknown for entries migrated from before metadata was stored.
+    pub acquired_at: Option<DateTime<Utc>>,
+}
+
+impl CredentialsMetadata {
+    fn new(role_info: &RoleInfo, acquired_at: Option<DateTime<Utc>>) -> Self {
+        Self {
+            aws_partition: role_info.aws_partition.clone(),
+            account_id: role_info.account_id.clone(),
+            role_name: role_info.role_name.clone(),
+            region: role_info.region.clone(),
+            tenant_id: role_info.azure_account.tenant_id.clone(),
+            azure_username: role_info.azure_account.username.clone(),
+            assume_method: role_info.assume_method.as_str().to_string(),
+            sts_endpoint_url: role_info.sts_endpoint.endpoint_url.clone(),
+            acquired_at,
+        }
+    }
+
+    // Everything that makes STS return other credentials. The region does not, as credentials are
+    // valid in every region of the partition.
+    fn key(&self) -> String {
+        let inputs = [
+            Some(self.aws_partition.as_str()),
+            Some(self.account_id.as_str()),
+            Some(self.role_name.as_str()),
+            self.tenant_id.as_deref(),
+            self.azure_username.as_deref(),
+            Some(self.assume_method.as_str()),
+            self.sts_endpoint_url.as_deref(),
+        ];
+        let inputs = serde_json::to_vec(&inputs).unwrap_or_default();
+        let digest = ring::digest::digest(&ring::digest::SHA256, &inputs);
+        digest.as_ref()[..8].iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+}
+
+pub struct CredentialsCache {
+    store: Box<dyn CredentialStore>,
+    entry_name: String,
+    // The name used before entries were keyed on all inputs, for migrating them
+    legacy_entry_name: String,
+    metadata: CredentialsMetadata,
+    // Entries in memory are not shared with other processes, so they need no lock file
+    lock_file_path: Option<PathBuf>,
+}
+
+impl CredentialsCache {
+    pub fn new(role_info: &RoleInfo) -> Result<Self> {
+        let lock_directory = match role_info.cache_settings.store {
+            StoreKind::Memory => None,
+            _ => {
+                Self::create_cache_dir()?;
+                Some(Self::directory()?)
+            }
+        };
+        Ok(Self::with_store(role_info, open_store(&role_info.cache_settings)?, lock_directory))
+    }
+
+    // Lock files are kept in `lock_directory`, if there is one
+    fn with_store(role_info: &RoleInfo, store: Box<dyn CredentialStore>, lock_directory: Option<PathBuf>) -> Self {
+        let metadata = CredentialsMetadata::new(role_info, None);
+        // The account and role keep the names recognizable, the key tells apart the rest
+        let entry_name = format!(
+            "{account_id}-{role_name}-{key}.creds",
+            account_id = role_info.account_id,
+            role_name = file_name_component(&role_info.role_name),
+            key = metadata.key(),
+        );
+        let legacy_entry_name = format!(
+            "{account_id}-{role_name}{azure_account_suffix}.creds",
+            account_id = role_info.account_id,
+            role_name = file_name_component(&role_info.role_name),
+            azure_account_suffix = azure_account_suffix(&role_info.azure_account),
+        );
+        let lock_file_path = lock_directory.map(|directory| directory.join(format!("{entry_name}.lock")));
+        Self {
+            store,
+            entry_name,
+            legacy_entry_name,
+            metadata,
+            lock_file_path,
+        }
+    }
+
+    pub fn directory() -> Result<std::path::PathBuf> {
+        let pkg_name = env!("CARGO_PKG_NAME");
+        let dir = dirs::cache_dir()
+            .ok_or(CachedCredentialsError::UnsupportedPlatform)?
+            .join(pkg_name);
+        Ok(dir)
+    }
+
+    pub fn store_credentials(&self, credentials: &TemporaryAwsCredentials) -> Result<()> {
+        debug!("Storing creds as {} in {}", self.entry_name, self.store.location());
+        self.store_entry(credentials, Some(Utc::now()))
+    }
+
+    fn store_entry(&self, credentials: &TemporaryAwsCredentials, acquired_at: Option<DateTime<Utc>>) -> Result<()> {
+        let metadata = CredentialsMetadata {
+            acquired_at,
+            ..self.metadata.clone()
+        };
+        let envelope = Envelope::new(credentials, credentials.expiration, Some(metadata));
+        store_entry(self.store.as_ref(), &self.entry_name, &envelope)
+    }
+
+    /// Lock the cache entry, so that only one process acquires credentials for it at a time.
+    /// Waits up to `timeout` for another process to release the lock, and returns `None` if it
+    /// does not.
+    pub async fn lock(&self, timeout: Duration) -> Result<Option<CacheLock>> {
+        let Some(lock_file_path) = &self.lock_file_path else {
+            return Ok(Some(CacheLock { _file: None }));
+        };
+        let start = Instant::now();
+        let mut waiting = false;
+        loop {
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(false)
+                .open(lock_file_path)?;
+            match file.try_lock() {
+                Ok(()) if is_current_file(&file, lock_file_path) => {
+                    debug!("Locked {}", lock_file_path.display());
+                    return Ok(Some(CacheLock { _file: Some(file) }));
+                }
+                // Removed by `cache clear` while waiting for it, so lock the new file at the path
+                Ok(()) => debug!("Lock file {} was removed, locking it again", lock_file_path.display()),
+                Err(TryLockError::WouldBlock) if start.elapsed() < timeout => {
+                    if !waiting {
+                        log::info!("Waiting for another process to acquire the credentials");
+                        waiting = true;
+                    }
+                    tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+                }
+                Err(TryLockError::WouldBlock) => return Ok(None),
+                Err(TryLockError::Error(error)) => return Err(error.into()),
+            }
+        }
+    }
+
+    /// The cached credentials, for the region of the role info.
+    pub fn credentials(&self) -> Result<TemporaryAwsCredentials> {
+        let envelope: Envelope<serde_json::Value> = match load_entry(self.store.as_ref(), &self.entry_name) {
+            Err(CachedCredentialsError::NotFound(_)) => self.migrate_legacy_entry()?,
+            entry => entry?,
+        };
+        // Checked before reading the credentials, which can not be read once expired
+        if envelope.expiration <= Utc::now() {
+            return Err(CachedCredentialsError::Expired(envelope.expiration));
+        }
+        let credentials: TemporaryAwsCredentials = serde_json::from_value(envelope.entry)?;
+        Ok(TemporaryAwsCredentials {
+            region: self.metadata.region.clone(),
+            ..credentials
+        })
+    }
+
+    // Entries named by account, role and Azure account only are moved to the current name. They
+    // can not be told apart by the other inputs, which were rarely used when they were written.
+    fn migrate_legacy_entry(&self) -> Result<Envelope<serde_json::Value>> {
+        let envelope: Envelope<serde_json::Value> = match load_entry(self.store.as_ref(), &self.legacy_entry_name) {
+            Err(CachedCredentialsError::NotFound(_)) => {
+                return Err(CachedCredentialsError::NotFound(self.entry_name.clone()))
+            }
+            envelope => envelope?,
+        };
+        log::info!("Migrating cache entry {} to {}", self.legacy_entry_name, self.entry_name);
+        let metadata = CredentialsMetadata {
+            acquired_at: envelope.metadata.as_ref().and_then(|metadata| metadata.acquired_at),
+            ..self.metadata.clone()
+        };
+        let envelope = Envelope::new(envelope.entry, envelope.expiration, Some(metadata));
+        store_entry(self.store.as_ref(), &self.entry_name, &envelope)?;
+        self.store.remove(&self.legacy_entry_name)?;
+        Ok(envelope)
+    }
+
+    fn create_cache_dir() -> Result<()> {
+        create_private_dir(&Self::directory()?)?;
+        Ok(())
+    }
+}
+
+pub struct OidcTokenCache {
+    store: Box<dyn CredentialStore>,
+    entry_name: String,
+}
+
+impl OidcTokenCache {
+    pub fn new(settings: &CacheSettings, azure_account: &AzureAccount, audience: &str) -> Result<Self> {
+        let audience: String = audience
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+        let entry_name = format!(
+            "{audience}{azure_account_suffix}.token",
+            azure_account_suffix = azure_account_suffix(azure_account),
+        );
+        Ok(Self {
+            store: open_store(settings)?,
+            entry_name,
+        })
+    }
+
+    pub fn store_token(&self, token: &OidcToken) -> Result<()> {
+        debug!("Storing Azure AD token as {} in {}", self.entry_name, self.store.location());
+        store_entry(self.store.as_ref(), &self.entry_name, &Envelope::new(token, token.expiration, None))
+    }
+
+    pub fn token(&self) -> Result<OidcToken> {
+        load_entry(self.store.as_ref(), &self.entry_name).map(|envelope| envelope.entry)
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        debug!("Removing Azure AD token {} from {}", self.entry_name, self.store.location());
+        self.store.remove(&self.entry_name)
+    }
+}
+
+pub struct SamlAssertionCache {
+    store: Box<dyn CredentialStore>,
+    entry_name: String,
+}
+
+impl SamlAssertionCache {
+    pub fn new(settings: &CacheSettings, account_id: &str, azure_account: &AzureAccount) -> Result<Self> {
+        let entry_name = format!(
+            "{account_id}{azure_account_suffix}.saml",
+            azure_account_suffix = azure_account_suffix(azure_account),
+        );
+        Ok(Self {
+            store: open_store(settings)?,
+            entry_name,
+        })
+    }
+
+    pub fn store_assertion(&self, assertion: &SamlAssertion) -> Result<()> {
+        debug!("Storing SAML assertion as {} in {}", self.entry_name, self.store.location());
+        let envelope = Envelope::new(assertion, assertion.not_on_or_after, None);
+        store_entry(self.store.as_ref(), &self.entry_name, &envelope)
+    }
+
+    pub fn assertion(&self) -> Result<SamlAssertion> {
+        load_entry(self.store.as_ref(), &self.entry_name).map(|envelope| envelope.entry)
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        debug!("Removing SAML assertion {} from {}", self.entry_name, self.store.location());
+        self.store.remove(&self.entry_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::RoleInfoBuilder;
+
+    fn role_info() -> RoleInfo {
+        RoleInfoBuilder::default()
+            .aws_partition(String::from("aws"))
+            .role_name(String::from("platform/deployer"))
+            .account_id(String::from("123456789012"))
+            .region(String::from("eu-central-1"))
+            .duration(3600)
+            .build()
+            .unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn migrates_world_readable_legacy_entry() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        let expiration = Utc::now() + chrono::Duration::hours(1);
+        // As written by versions before cache files were private: bare credentials with the
+        // default permissions, named by account and role only
+        let legacy_path = directory.path().join("123456789012-platform%2Fdeployer.creds");
+        let contents = format!(
+            r#"{{"Version":1,"AccessKeyId":"AKIA","SecretAccessKey":"secret","SessionToken":"token","Expiration":"{}"}}"#,
+            expiration.to_rfc3339()
+        );
+        std::fs::write(&legacy_path, contents).unwrap();
+        std::fs::set_permissions(&legacy_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let store = files::FileStore::in_directory(directory.path().to_path_buf()).unwrap();
+        let cache = CredentialsCache::with_store(&role_info(), Box::new(store), None);
+        let credentials = cache.credentials().unwrap();
+
+        assert_eq!(credentials.access_key_id, "AKIA");
+        assert_eq!(credentials.region, "eu-central-1");
+        assert!(!legacy_path.exists());
+        let path = directory.path().join(&cache.entry_name);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o600);
+        let contents = std::fs::read(&path).unwrap();
+        let (envelope, upgraded) = format::parse::<TemporaryAwsCredentials>(&cache.entry_name, &contents).unwrap();
+        assert!(!upgraded);
+        assert_eq!(envelope.metadata.unwrap().role_name, "platform/deployer");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn notices_removed_lock_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("entry.creds.lock");
+        let file = File::create(&path).unwrap();
+        assert!(is_current_file(&file, &path));
+        std::fs::remove_file(&path).unwrap();
+        assert!(!is_current_file(&file, &path));
+        File::create(&path).unwrap();
+        assert!(!is_current_file(&file, &path));
+    }
+
+    #[test]
+    fn removes_rejected_tokens() {
+        let settings = CacheSettings {
+            store: StoreKind::Memory,
+            ..Default::default()
+        };
+        let cache = OidcTokenCache::new(&settings, &AzureAccount::default(), "removes-rejected-tokens").unwrap();
+        let token = OidcToken {
+            token: String::from("eyJ0"),
+            audience: String::from("removes-rejected-tokens"),
+            expiration: Utc::now() + chrono::Duration::hours(1),
+        };
+        cache.store_token(&token).unwrap();
+        assert_eq!(cache.token().unwrap().token, "eyJ0");
+        cache.remove().unwrap();
+        assert!(matches!(cache.token(), Err(CachedCredentialsError::NotFound(_))));
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::fs::{File, TryLockError};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use log::info;
+
+use super::list::{list_credentials, read_summary, CredentialsListing};
+use super::{CachedCredentialsError, CredentialStore, CredentialsCache, Result};
+
+/// Which credentials entries `cache clear` removes. Entries must match all given criteria, and
+/// all entries are removed if none are given.
+#[derive(Debug, Default)]
+pub struct EntryFilter {
+    pub account_id: Option<String>,
+    pub role_name: Option<String>,
+    pub expired: bool,
+}
+
+impl EntryFilter {
+    pub fn is_empty(&self) -> bool {
+        self.account_id.is_none() && self.role_name.is_none() && !self.expired
+    }
+
+    fn matches(&self, listing: &CredentialsListing) -> bool {
+        let matches = |wanted: &Option<String>, actual: &Option<String>| wanted.is_none() || wanted == actual;
+        matches(&self.account_id, &listing.account_id)
+            && matches(&self.role_name, &listing.role_name)
+            && (!self.expired || listing.expired == Some(true))
+    }
+}
+
+// Entries that can never be used again, including files of other users that other users can
+// access. Entries encrypted for another store or with another key are fine for that store or key,
+// and entries in newer formats for newer versions, so they are kept.
+fn is_unusable(error: &CachedCredentialsError) -> bool {
+    matches!(
+        error,
+        CachedCredentialsError::JsonError(_)
+            | CachedCredentialsError::InsecurePermissions { .. }
+            | CachedCredentialsError::MalformedEntry(_)
+            | CachedCredentialsError::DecryptionFailed(_)
+            | CachedCredentialsError::TamperedEntry(_)
+    )
+}
+
+/// The entries and lock files to remove from a store. Only entries with the names of the tool's
+/// own entries, and lock files of credentials entries, are ever included.
+#[derive(Debug)]
+pub struct Removal {
+    pub entries: Vec<String>,
+    pub lock_files: Vec<PathBuf>,
+}
+
+impl Removal {
+    /// The entries matching the filter, with their lock files. Without criteria, all entries of
+    /// the store and all lock files in the cache directory.
+    pub fn matching(store: &dyn CredentialStore, filter: &EntryFilter) -> Result<Self> {
+        if filter.is_empty() {
+            return Ok(Self {
+                entries: store.names()?,
+                lock_files: all_lock_files()?,
+            });
+        }
+        let entries = list_credentials(store, &HashMap::new())?
+            .into_iter()
+            .filter(|listing| filter.matches(listing))
+            .map(|listing| listing.name)
+            .collect();
+        Self::with_lock_files(entries)
+    }
+
+    /// The entries that have expired or can not be read, with their lock files.
+    pub fn prunable(store: &dyn CredentialStore) -> Result<Self> {
+        let now = Utc::now();
+        let mut entries = Vec::new();
+        for name in store.names()? {
+            match read_summary(store, &name) {
+                Ok(summary) if summary.expiration <= now => entries.push(name),
+                Err(error) if is_unusable(&error) => entries.push(name),
+                _ => {}
+            }
+        }
+        Self::with_lock_files(entries)
+    }
+
+    fn with_lock_files(entries: Vec<String>) -> Result<Self> {
+        let directory = CredentialsCache::directory()?;
+        let lock_files = entries
+            .iter()
+            .map(|name| directory.join(format!("{name}.lock")))
+            .filter(|path| path.exists())
+            .collect();
+        Ok(Self { entries, lock_files })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.lock_files.is_empty()
+    }
+
+    /// One line per entry and lock file, for confirming the removal.
+    pub fn describe(&self, store: &dyn CredentialStore) -> String {
+        let entries = self
+            .entries
+            .iter()
+            .map(|name| format!("  {name} in {}", store.location()));
+        let lock_files = self
+            .lock_files
+            .iter()
+            .map(|path| format!("  lock file {}", path.display()));
+        entries.chain(lock_files).collect::<Vec<_>>().join("\n")
+    }
+
+    pub fn execute(&self, store: &dyn CredentialStore) -> Result<()> {
+        for name in &self.entries {
+            info!("Deleting cache entry {name} from {}", store.location());
+            store.remove(name)?;
+        }
+        for path in &self.lock_files {
+            if remove_unlocked(path)? {
+                info!("Deleted lock file {}", path.display());
+            } else {
+                info!("Keeping lock file {}, which another process holds", path.display());
+            }
+        }
+        Ok(())
+    }
+}
+
+// Lock files are only removed while holding the lock, so that no process is acquiring credentials
+// with it. Processes waiting for the lock notice the removal and lock the file at the path again.
+fn remove_unlocked(path: &Path) -> Result<bool> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(true),
+        Err(error) => return Err(error.into()),
+    };
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => return Ok(false),
+        Err(TryLockError::Error(error)) => return Err(error.into()),
+    }
+    match std::fs::remove_file(path) {
+        Err(error) if error.kind() != ErrorKind::NotFound => Err(error.into()),
+        _ => Ok(true),
+    }
+}
+
+// Lock files are named after the credentials entry they lock
+fn all_lock_files() -> Result<Vec<PathBuf>> {
+    let directory = CredentialsCache::directory()?;
+    if !directory.exists() {
+        return Ok(Vec::new());
+    }
+    let mut lock_files = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.ends_with(".creds.lock"))
+        {
+            lock_files.push(path);
+        }
+    }
+    lock_files.sort();
+    Ok(lock_files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_lock_files_held_by_other_processes() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("123456789012-deployer-0123456789abcdef.creds.lock");
+        let held = File::create(&path).unwrap();
+        held.lock().unwrap();
+        assert!(!remove_unlocked(&path).unwrap());
+        assert!(path.exists());
+
+        held.unlock().unwrap();
+        assert!(remove_unlocked(&path).unwrap());
+        assert!(!path.exists());
+    }
+}
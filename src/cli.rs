@@ -1,14 +1,19 @@
-use clap::{Parser, Subcommand, ValueHint};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand, ValueHint};
 use clap_complete::Shell;
 use clap_verbosity_flag::Verbosity;
 
 use crate::defaults::{
-    DEFAULT_DURATION,
+    VALID_ASSUME_METHODS,
+    VALID_LOGIN_MODES,
     VALID_AWS_PARTITIONS,
-    DEFAULT_AWS_PARTITION,
-    DEFAULT_REGION,
     ENV_VARS_STYLES,
+    VALID_CACHE_STORES,
 };
+use crate::models::{parse_duration, RoleArn};
 
 #[derive(Debug, Parser)]
 #[command(name="aws-credentials-cli")]
@@ -27,48 +32,109 @@ pub struct Cli {
 }
 
 #[derive(Debug, Subcommand)]
+#[allow(clippy::large_enum_variant)]
 pub enum Commands {
     /// Credentials cache operations. If no subcommand is given then it defaults to 'path'.
     Cache {
+        /// The cache store to operate on. The default is the store of the config file.
+        #[arg(long, global = true, value_parser = VALID_CACHE_STORES)]
+        store: Option<String>,
+
         #[command(subcommand)]
         command: Option<CacheCommands>,
     },
     /// Assume role on account to get temporary credentials.
     Assume {
-        /// The AWS partition for the account
-        #[arg(long)]
-        #[arg(value_parser = VALID_AWS_PARTITIONS)]
-        #[arg(default_value_t = String::from(DEFAULT_AWS_PARTITION))]
-        aws_partition: String,
+        #[command(flatten)]
+        role_args: RoleArgs,
 
-        /// Assume role on account to get temporary credentials.
+        /// Force fetching new credentials regardless of non-expired cached credentials.
         #[arg(short, long)]
-        account: String,
+        force: bool,
 
-        /// The role to assume.
-        #[arg(short, long)]
-        role: String,
+        /// Output format
+        #[command(subcommand)]
+        output_as: Option<OutputAsCommands>,
+        // #[arg(short, long, default_value = "json")]
+        // #[arg(value_parser = ["json", "credentials-file", "env-vars"])]
+        // output: String,
+    },
+    /// Run a command with temporary credentials for the role in its environment. Starts an
+    /// interactive subshell if no command is given.
+    Exec {
+        #[command(flatten)]
+        role_args: RoleArgs,
 
-        /// The AWS session duration in seconds. Must be minimum 900 seconds (15 minutes).
+        /// Force fetching new credentials regardless of non-expired cached credentials.
         #[arg(short, long)]
-        #[arg(value_parser = clap::value_parser!(i32).range(900..))]
-        #[arg(default_value_t = DEFAULT_DURATION)]
-        duration: i32,
+        force: bool,
+
+        /// The command to run and its arguments, e.g. '-- terraform plan'.
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, value_hint = ValueHint::CommandWithArguments)]
+        command: Vec<String>,
+    },
+    /// Serve temporary credentials for the role for the container credentials provider of the AWS
+    /// SDKs and CLI, or like the EC2 instance metadata service, until stopped. The credentials are
+    /// refreshed before they expire. Prints the environment variables that make clients use it.
+    Serve {
+        #[command(flatten)]
+        role_args: RoleArgs,
+
+        /// Serve the credentials like the EC2 instance metadata service (IMDSv2) instead, for
+        /// tools that do not support the container credentials provider.
+        #[arg(long)]
+        imds: bool,
+
+        /// The IP address to listen on. Clients of the container credentials provider only accept
+        /// loopback addresses.
+        #[arg(long, default_value = "127.0.0.1")]
+        address: IpAddr,
+
+        /// The port to listen on. The default is a free port chosen by the system.
+        #[arg(long, default_value_t = 0)]
+        port: u16,
 
-        /// The region to use.
-        #[arg(long, default_value_t = DEFAULT_REGION.to_string())]
-        region: String,
+        /// The shell type to output the environment variables for.
+        #[arg(short, long, value_parser = ENV_VARS_STYLES, default_value = "sh")]
+        style: String,
+    },
+    /// Sign in to the AWS console with temporary credentials for the role. Prints the sign-in URL
+    /// unless '--open' is given.
+    Console {
+        #[command(flatten)]
+        role_args: RoleArgs,
 
         /// Force fetching new credentials regardless of non-expired cached credentials.
         #[arg(short, long)]
         force: bool,
 
-        /// Output format
+        /// The console page to go to: a service, e.g. 'ec2', a console path, e.g. 's3/buckets', or
+        /// a full console URL. The default is the console home.
+        #[arg(long)]
+        destination: Option<String>,
+
+        /// The URL the console links to when the session expires.
+        #[arg(long)]
+        issuer: Option<String>,
+
+        /// The console session duration in seconds, from 900 to 43200.
+        #[arg(long)]
+        #[arg(value_parser = clap::value_parser!(u32).range(900..=43200))]
+        session_duration: Option<u32>,
+
+        /// Open the sign-in URL in the default browser instead of printing it.
+        #[arg(long)]
+        open: bool,
+
+        /// Print the URL for signing out of the console instead of signing in.
+        #[arg(long, conflicts_with_all = ["force", "destination", "issuer", "session_duration"])]
+        logout: bool,
+    },
+    /// Keep credentials for the profiles marked 'keep_warm' in the config file fresh in the cache
+    /// in the background.
+    Daemon {
         #[command(subcommand)]
-        output_as: Option<OutputAsCommands>,
-        // #[arg(short, long, default_value = "json")]
-        // #[arg(value_parser = ["json", "credentials-file", "env-vars"])]
-        // output: String,
+        command: DaemonCommands,
     },
     /// Generate completion scripts for a supported shell.
     /// Redirect the output to a suitable directory for your shell and run the intitialization
@@ -85,16 +151,198 @@ pub enum Commands {
     }
 }
 
+/// Options selecting the role to assume and how to assume it. Options not given on the command
+/// line are taken from the profile given by `--profile-name`, and otherwise from the defaults.
+#[derive(Debug, Default, Args)]
+pub struct RoleArgs {
+    /// The profile in the config file to take options from.
+    #[arg(long)]
+    pub profile_name: Option<String>,
+
+    /// The AWS partition for the account. The default is the partition of the region, or 'aws'
+    /// if no region is given.
+    #[arg(long)]
+    #[arg(value_parser = VALID_AWS_PARTITIONS)]
+    pub aws_partition: Option<String>,
+
+    /// The ARN of the role to assume, e.g. 'arn:aws:iam::123456789012:role/platform/deployer'.
+    /// The account, partition and role name, including the path, are taken from the ARN.
+    #[arg(long, conflicts_with_all = ["account", "role", "aws_partition"])]
+    pub role_arn: Option<RoleArn>,
+
+    /// Assume role on account to get temporary credentials.
+    #[arg(short, long, required_unless_present_any = ["profile_name", "role_arn"])]
+    pub account: Option<String>,
+
+    /// The role to assume. Roles with a path are given as 'path/name', e.g. 'platform/deployer'.
+    #[arg(short, long, required_unless_present_any = ["profile_name", "role_arn"])]
+    pub role: Option<String>,
+
+    /// The AWS session duration in seconds. Must be minimum 900 seconds (15 minutes). The default
+    /// is 3600 seconds.
+    #[arg(short, long)]
+    #[arg(value_parser = clap::value_parser!(i32).range(900..))]
+    pub duration: Option<i32>,
+
+    /// The region to use. The default depends on the partition: 'eu-west-1' for 'aws',
+    /// 'cn-north-1' for 'aws-cn', and 'us-gov-west-1' for 'aws-us-gov'.
+    #[arg(long)]
+    pub region: Option<String>,
+
+    /// How to assume the role. 'saml' (default) uses the token exchange service to get a SAML
+    /// assertion. 'web-identity' passes the Azure AD token directly to STS and requires an IAM
+    /// OIDC identity provider for Azure AD on the account.
+    #[arg(long)]
+    #[arg(value_parser = VALID_ASSUME_METHODS)]
+    pub assume_method: Option<String>,
+
+    /// When to run the login command if no Azure AD token can be acquired. 'auto' (default) logs
+    /// in when running in a terminal and fails otherwise, e.g. when running as `credential_process`.
+    #[arg(long)]
+    #[arg(value_parser = VALID_LOGIN_MODES)]
+    pub login: Option<String>,
+
+    /// The command used for logging in to Azure. The default is 'az'.
+    #[arg(long)]
+    pub login_command: Option<String>,
+
+    /// An argument for the login command. Can be used multiple times. The default is 'login'.
+    #[arg(long = "login-arg", value_name = "LOGIN_ARG", allow_hyphen_values = true)]
+    pub login_args: Option<Vec<String>>,
+
+    /// The Azure AD tenant ID. Used for acquiring the Azure AD token and passed as `--tenant` to
+    /// the login command.
+    #[arg(long)]
+    pub tenant: Option<String>,
+
+    /// The Azure username to acquire the Azure AD token for when logged in with several accounts.
+    /// Passed as `--username` to the login command.
+    #[arg(long)]
+    pub azure_username: Option<String>,
+
+    /// Log in using the device code flow. Passes `--use-device-code` to the login command.
+    #[arg(long)]
+    pub use_device_code: bool,
+
+    /// Cache the SAML assertion from the token exchange until it expires, so that assuming
+    /// another role in the same account skips the token exchange.
+    #[arg(long)]
+    pub cache_saml_assertion: bool,
+
+    /// Timeout in seconds for connecting to the token exchange service and STS. The default is
+    /// 10 seconds.
+    #[arg(long, value_name = "SECONDS")]
+    pub connect_timeout: Option<u64>,
+
+    /// Timeout in seconds for a single request to the token exchange service or STS. The default
+    /// is 30 seconds.
+    #[arg(long, value_name = "SECONDS")]
+    pub request_timeout: Option<u64>,
+
+    /// Deadline in seconds for acquiring credentials, including retries and logging in. The
+    /// default is 300 seconds.
+    #[arg(long, value_name = "SECONDS")]
+    pub timeout: Option<u64>,
+
+    /// Maximum number of attempts for requests failing with retryable errors, e.g. server errors,
+    /// throttling, and connection failures. The default is 3.
+    #[arg(long)]
+    #[arg(value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_attempts: Option<u32>,
+
+    /// Proxy for the token exchange service, Azure, and STS, e.g. 'http://proxy.example.com:8080'.
+    /// The default is to use the `HTTPS_PROXY` and `NO_PROXY` environment variables.
+    #[arg(long)]
+    pub proxy: Option<String>,
+
+    /// PEM file with extra CA certificates to trust, e.g. for a TLS-inspecting proxy.
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    pub ca_bundle: Option<PathBuf>,
+
+    /// Custom STS endpoint, e.g. a VPC interface endpoint.
+    #[arg(long, value_hint = ValueHint::Url)]
+    pub sts_endpoint_url: Option<String>,
+
+    /// Use the FIPS endpoint for STS.
+    #[arg(long)]
+    pub use_fips_endpoint: bool,
+
+    /// Use the dual-stack (IPv4 and IPv6) endpoint for STS.
+    #[arg(long)]
+    pub use_dualstack_endpoint: bool,
+
+    /// Acquire new credentials if the cached ones expire within this time, e.g. '300', '90s',
+    /// '10m' or '1h'. The default is 5 minutes.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub min_remaining: Option<Duration>,
+
+    /// Keep credentials and tokens in memory only, instead of in the cache store of the config
+    /// file. Nothing is reused by or left for later runs.
+    #[arg(long)]
+    pub no_cache: bool,
+}
+
 #[derive(Debug, Subcommand)]
 pub enum CacheCommands {
-    /// Clears the credentials cache. Deletes all files in the cache directory.
+    /// Clears the credentials cache. Deletes all entries in the cache store, or the credentials
+    /// matching the filters.
     Clear {
         /// Do not ask for permission.
         #[arg(short, long)]
         yes: bool,
+        /// Only delete credentials for this account.
+        #[arg(long)]
+        account: Option<String>,
+        /// Only delete credentials for this role.
+        #[arg(long)]
+        role: Option<String>,
+        /// Only delete credentials for the account and role of this profile.
+        #[arg(long)]
+        profile_name: Option<String>,
+        /// Only delete expired credentials.
+        #[arg(long)]
+        expired: bool,
+    },
+    /// Deletes the entries that have expired or can not be read.
+    Prune {
+        /// Do not ask for permission.
+        #[arg(short, long)]
+        yes: bool,
     },
     /// Prints the cache directory path.
     Path,
+    /// Lists the cached credentials with their account, role, region and expiration.
+    List {
+        /// Print the entries as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DaemonCommands {
+    /// Start the daemon in the background.
+    Start {
+        #[command(flatten)]
+        daemon_args: DaemonArgs,
+    },
+    /// Run the daemon in the foreground, e.g. from a service manager.
+    Run {
+        #[command(flatten)]
+        daemon_args: DaemonArgs,
+    },
+    /// Stop the daemon running in the background.
+    Stop,
+    /// Show whether the daemon is running and the state of the profiles it keeps fresh.
+    Status,
+}
+
+#[derive(Debug, Args)]
+pub struct DaemonArgs {
+    /// Acquire new credentials this many seconds before the cached ones expire. Can be set per
+    /// profile with 'refresh_before_expiry'. The default is 600 seconds.
+    #[arg(long, value_name = "SECONDS")]
+    pub refresh_before_expiry: Option<u64>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use configparser::ini::{Ini, IniDefault};
+use log::debug;
+
+use crate::cache::CacheSettings;
+use crate::models::{parse_duration, RoleArn};
+
+pub type Result<T> = std::result::Result<T, ConfigError>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Failed to load config file {0}: {1}")]
+    LoadFailed(PathBuf, String),
+    #[error("Profile '{0}' not found in config file {1}")]
+    ProfileNotFound(String, PathBuf),
+    #[error("Invalid value for '{key}' in profile '{profile}': {message}")]
+    InvalidValue {
+        profile: String,
+        key: String,
+        message: String,
+    },
+    #[error("Invalid value for '{key}' in section '{section}': {message}")]
+    InvalidSetting {
+        section: String,
+        key: String,
+        message: String,
+    },
+    #[error("Unsupported platform")]
+    UnsupportedPlatform,
+}
+
+/// A named set of `assume` options read from the config file. Options given on the command line
+/// take precedence over the ones in the profile.
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    pub role_arn: Option<RoleArn>,
+    pub account: Option<String>,
+    pub role: Option<String>,
+    pub aws_partition: Option<String>,
+    pub region: Option<String>,
+    pub duration: Option<i32>,
+    pub assume_method: Option<String>,
+    pub login: Option<String>,
+    pub login_command: Option<String>,
+    pub login_args: Option<Vec<String>>,
+    pub tenant_id: Option<String>,
+    pub azure_username: Option<String>,
+    pub use_device_code: Option<bool>,
+    pub cache_saml_assertion: Option<bool>,
+    pub connect_timeout: Option<u64>,
+    pub request_timeout: Option<u64>,
+    pub timeout: Option<u64>,
+    pub max_attempts: Option<u32>,
+    pub proxy: Option<String>,
+    pub ca_bundle: Option<PathBuf>,
+    pub sts_endpoint_url: Option<String>,
+    pub use_fips_endpoint: Option<bool>,
+    pub use_dualstack_endpoint: Option<bool>,
+    pub min_remaining: Option<Duration>,
+    /// Keep the credentials fresh in the cache with the daemon.
+    pub keep_warm: Option<bool>,
+    pub refresh_before_expiry: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    path: PathBuf,
+    ini: Ini,
+}
+
+impl Config {
+    pub fn path() -> Result<PathBuf> {
+        let pkg_name = env!("CARGO_PKG_NAME");
+        let path = dirs::config_dir()
+            .ok_or(ConfigError::UnsupportedPlatform)?
+            .join(pkg_name)
+            .join("config");
+        Ok(path)
+    }
+
+    /// Load the config file. A missing config file is treated as an empty one.
+    pub fn load() -> Result<Self> {
+        Self::load_from(&Self::path()?)
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self> {
+        let mut ini_defaults = IniDefault::default();
+        ini_defaults.default_section = String::from("no-section");
+        ini_defaults.case_sensitive = true;
+        let mut ini = Ini::new_from_defaults(ini_defaults);
+        if path.exists() {
+            debug!("Loading config file {}", path.display());
+            ini.load(path)
+                .map_err(|e| ConfigError::LoadFailed(path.to_path_buf(), e))?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            ini,
+        })
+    }
+
+    pub fn profile_names(&self) -> Vec<String> {
+        self.ini
+            .sections()
+            .iter()
+            .filter_map(|section| section.strip_prefix("profile "))
+            .map(str::to_string)
+            .collect()
+    }
+
+    pub fn profile(&self, name: &str) -> Result<Profile> {
+        let section = format!("profile {name}");
+        if !self.ini.sections().contains(&section) {
+            return Err(ConfigError::ProfileNotFound(name.to_string(), self.path.clone()));
+        }
+        let invalid = |key: &str, message: String| ConfigError::InvalidValue {
+            profile: name.to_string(),
+            key: key.to_string(),
+            message,
+        };
+        let get = |key: &str| self.ini.get(&section, key);
+        let get_bool = |key: &str| {
+            self.ini
+                .getboolcoerce(&section, key)
+                .map_err(|e| invalid(key, e))
+        };
+        let get_uint = |key: &str| {
+            self.ini
+                .getuint(&section, key)
+                .map_err(|e| invalid(key, e))
+        };
+
+        let duration = self
+            .ini
+            .getint(&section, "duration")
+            .map_err(|e| invalid("duration", e))?
+            .map(|duration| i32::try_from(duration).map_err(|e| invalid("duration", e.to_string())))
+            .transpose()?;
+        let max_attempts = get_uint("max_attempts")?
+            .map(|max_attempts| u32::try_from(max_attempts).map_err(|e| invalid("max_attempts", e.to_string())))
+            .transpose()?;
+
+        Ok(Profile {
+            role_arn: get("role_arn")
+                .map(|role_arn| role_arn.parse().map_err(|e| invalid("role_arn", e)))
+                .transpose()?,
+            account: get("account"),
+            role: get("role"),
+            aws_partition: get("aws_partition"),
+            region: get("region"),
+            duration,
+            assume_method: get("assume_method"),
+            login: get("login"),
+            login_command: get("login_command"),
+            login_args: get("login_args")
+                .map(|args| args.split_whitespace().map(str::to_string).collect()),
+            tenant_id: get("tenant_id"),
+            azure_username: get("azure_username"),
+            use_device_code: get_bool("use_device_code")?,
+            cache_saml_assertion: get_bool("cache_saml_assertion")?,
+            connect_timeout: get_uint("connect_timeout")?,
+            request_timeout: get_uint("request_timeout")?,
+            timeout: get_uint("timeout")?,
+            max_attempts,
+            proxy: get("proxy"),
+            ca_bundle: get("ca_bundle")
+                .map(|ca_bundle| PathBuf::from(shellexpand::tilde(&ca_bundle).as_ref())),
+            sts_endpoint_url: get("sts_endpoint_url"),
+            use_fips_endpoint: get_bool("use_fips_endpoint")?,
+            use_dualstack_endpoint: get_bool("use_dualstack_endpoint")?,
+            min_remaining: get("min_remaining")
+                .map(|min_remaining| parse_duration(&min_remaining).map_err(|e| invalid("min_remaining", e)))
+                .transpose()?,
+            keep_warm: get_bool("keep_warm")?,
+            refresh_before_expiry: get_uint("refresh_before_expiry")?,
+        })
+    }
+
+    /// The settings of the `[cache]` section. They apply to all profiles.
+    pub fn cache_settings(&self) -> Result<CacheSettings> {
+        let section = "cache";
+        let invalid = |key: &str, message: String| ConfigError::InvalidSetting {
+            section: section.to_string(),
+            key: key.to_string(),
+            message,
+        };
+        let get = |key: &str| self.ini.get(section, key);
+        Ok(CacheSettings {
+            store: get("store")
+                .map(|store| store.parse().map_err(|e| invalid("store", e)))
+                .transpose()?
+                .unwrap_or_default(),
+            keyring: get("keyring")
+                .map(|keyring| keyring.parse().map_err(|e| invalid("keyring", e)))
+                .transpose()?
+                .unwrap_or_default(),
+            key_source: get("key_source")
+                .map(|key_source| key_source.parse().map_err(|e| invalid("key_source", e)))
+                .transpose()?
+                .unwrap_or_default(),
+            key_file: get("key_file")
+                .map(|key_file| PathBuf::from(shellexpand::tilde(&key_file).as_ref())),
+        })
+    }
+
+    /// The account aliases of the `[account_aliases]` section, by account ID.
+    pub fn account_aliases(&self) -> HashMap<String, String> {
+        self.ini
+            .get_map_ref()
+            .get("account_aliases")
+            .into_iter()
+            .flatten()
+            .filter_map(|(account_id, alias)| Some((account_id.clone(), alias.clone()?)))
+            .collect()
+    }
+}
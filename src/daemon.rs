@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use chrono::{DateTime, Local, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::assume;
+use crate::cache::{CredentialsCache, StoreKind};
+use crate::cli::{DaemonArgs, RoleArgs};
+use crate::config::{Config, ConfigError};
+use crate::defaults::{DAEMON_CHECK_INTERVAL, DEFAULT_KEEP_WARM_REFRESH_BEFORE_EXPIRY, REFRESH_RETRY_DELAY};
+use crate::private_files::write_private_file;
+
+pub type Result<T> = std::result::Result<T, DaemonError>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DaemonError {
+    #[error(transparent)]
+    FileSystemError(#[from] std::io::Error),
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+    #[error(transparent)]
+    ConfigError(#[from] ConfigError),
+    #[error("The daemon is already running with process ID {0}")]
+    AlreadyRunning(u32),
+    #[error("The daemon is already starting")]
+    AlreadyStarting,
+    #[error("The daemon is not running")]
+    NotRunning,
+    #[error("Failed to stop the daemon with process ID {pid}: {message}")]
+    StopFailed { pid: u32, message: String },
+    #[error("Unsupported platform")]
+    UnsupportedPlatform,
+}
+
+/// The state of the daemon, written after every check so that `daemon status` can show it.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct DaemonStatus {
+    pid: u32,
+    started: DateTime<Utc>,
+    updated: DateTime<Utc>,
+    profiles: Vec<ProfileStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
+struct ProfileStatus {
+    profile: String,
+    role_arn: Option<String>,
+    expiration: Option<DateTime<Utc>>,
+    next_refresh: DateTime<Utc>,
+    error: Option<String>,
+}
+
+// The daemon files are kept out of the cache directory, so that clearing the cache leaves them
+fn directory() -> Result<PathBuf> {
+    let pkg_name = env!("CARGO_PKG_NAME");
+    let dir = dirs::data_local_dir()
+        .ok_or(DaemonError::UnsupportedPlatform)?
+        .join(pkg_name);
+    Ok(dir)
+}
+
+fn status_path() -> Result<PathBuf> {
+    Ok(directory()?.join("daemon.status"))
+}
+
+fn log_path() -> Result<PathBuf> {
+    Ok(directory()?.join("daemon.log"))
+}
+
+// The daemon locks this file while it runs, so the lock shows whether it is still running even if
+// its process ID has been reused by another process
+fn pid_lock_path() -> Result<PathBuf> {
+    Ok(directory()?.join("daemon.pid"))
+}
+
+fn read_status() -> Result<Option<DaemonStatus>> {
+    match File::open(status_path()?) {
+        Ok(file) => Ok(Some(serde_json::from_reader(file)?)),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error.into()),
+    }
+}
+
+fn write_status(status: &DaemonStatus) -> Result<()> {
+    write_private_file(&status_path()?, &serde_json::to_vec_pretty(status)?)?;
+    Ok(())
+}
+
+/// Lock the pid lock file for as long as the returned file is open. Returns `None` if another
+/// daemon holds the lock.
+fn lock_pid_file() -> Result<Option<File>> {
+    let file = OpenOptions::new().create(true).write(true).truncate(false).open(pid_lock_path()?)?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(error)) => Err(error.into()),
+    }
+}
+
+fn pid_file_is_locked() -> Result<bool> {
+    let file = match File::open(pid_lock_path()?) {
+        Ok(file) => file,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(error) => return Err(error.into()),
+    };
+    match file.try_lock_shared() {
+        Ok(()) => Ok(false),
+        Err(TryLockError::WouldBlock) => Ok(true),
+        Err(TryLockError::Error(error)) => Err(error.into()),
+    }
+}
+
+/// The status of the daemon if it is running.
+fn running_daemon() -> Result<Option<DaemonStatus>> {
+    if !pid_file_is_locked()? {
+        return Ok(None);
+    }
+    read_status()
+}
+
+/// Start the daemon as a background process that logs to a file.
+pub fn start(daemon_args: &DaemonArgs) -> Result<()> {
+    if let Some(status) = running_daemon()? {
+        return Err(DaemonError::AlreadyRunning(status.pid));
+    }
+    std::fs::create_dir_all(directory()?)?;
+    let log_path = log_path()?;
+    let log_file = OpenOptions::new().create(true).append(true).open(&log_path)?;
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command.args(["-vv", "daemon", "run"]);
+    if let Some(refresh_before_expiry) = daemon_args.refresh_before_expiry {
+        command.args(["--refresh-before-expiry", &refresh_before_expiry.to_string()]);
+    }
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(log_file);
+    detach(&mut command);
+    let child = command.spawn()?;
+    println!(
+        "Started the daemon with process ID {}. It logs to {}",
+        child.id(),
+        log_path.display()
+    );
+    Ok(())
+}
+
+// Run the daemon in its own process group, so that it is not stopped with the terminal
+#[cfg(unix)]
+fn detach(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+}
+
+#[cfg(windows)]
+fn detach(command: &mut Command) {
+    use std::os::windows::process::CommandExt;
+    const DETACHED_PROCESS: u32 = 0x0000_0008;
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+    command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+}
+
+pub fn stop() -> Result<()> {
+    let status = running_daemon()?.ok_or(DaemonError::NotRunning)?;
+    let pid = status.pid.to_string();
+    let output = if cfg!(target_os = "windows") {
+        Command::new("taskkill").args(["/PID", &pid, "/F"]).output()?
+    } else {
+        Command::new("kill").arg(&pid).output()?
+    };
+    if !output.status.success() {
+        return Err(DaemonError::StopFailed {
+            pid: status.pid,
+            message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    std::fs::remove_file(status_path()?)?;
+    println!("Stopped the daemon with process ID {pid}");
+    Ok(())
+}
+
+fn local_time(time: &DateTime<Utc>) -> String {
+    time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+pub fn status() -> Result<()> {
+    let Some(status) = running_daemon()? else {
+        println!("The daemon is not running");
+        return Ok(());
+    };
+    println!(
+        "The daemon is running with process ID {} since {}. Last check at {}",
+        status.pid,
+        local_time(&status.started),
+        local_time(&status.updated)
+    );
+    if status.profiles.is_empty() {
+        println!("No profiles are marked 'keep_warm' in the config file");
+    }
+    for profile in status.profiles {
+        let role = profile.role_arn.as_deref().unwrap_or("unknown role");
+        let expiration = profile
+            .expiration
+            .map(|expiration| format!("expires at {}", local_time(&expiration)))
+            .unwrap_or(String::from("no credentials"));
+        println!(
+            "{}: {role}, {expiration}, next refresh at {}",
+            profile.profile,
+            local_time(&profile.next_refresh)
+        );
+        if let Some(error) = profile.error {
+            println!("    Last refresh failed: {error}");
+        }
+    }
+    Ok(())
+}
+
+/// Keep the credentials of one profile fresh. Returns when the profile needs attention next.
+async fn refresh_profile(
+    name: &str,
+    config: &Config,
+    default_refresh_before_expiry: u64,
+    failures: &mut HashMap<String, (String, DateTime<Utc>)>,
+) -> ProfileStatus {
+    let now = Utc::now();
+    let mut status = ProfileStatus {
+        profile: name.to_string(),
+        role_arn: None,
+        expiration: None,
+        next_refresh: now + chrono::Duration::seconds(REFRESH_RETRY_DELAY as i64),
+        error: None,
+    };
+    let refresh_before_expiry = match config.profile(name) {
+        Ok(profile) => profile.refresh_before_expiry.unwrap_or(default_refresh_before_expiry),
+        Err(error) => {
+            status.error = Some(error.to_string());
+            return status;
+        }
+    };
+    // There is no one to log in to Azure in the background
+    let role_args = RoleArgs {
+        profile_name: Some(name.to_string()),
+        login: Some(String::from("never")),
+        ..Default::default()
+    };
+    let role_info = match crate::role_info(role_args) {
+        Ok(role_info) => role_info,
+        Err(error) => {
+            status.error = Some(error.to_string());
+            return status;
+        }
+    };
+    status.role_arn = Some(role_info.role_arn());
+    // Refreshing earlier than halfway through the session would refresh again right away
+    let refresh_before_expiry =
+        chrono::Duration::seconds((refresh_before_expiry as i64).min(i64::from(role_info.duration) / 2));
+    let cache = match CredentialsCache::new(&role_info) {
+        Ok(cache) => cache,
+        Err(error) => {
+            status.error = Some(error.to_string());
+            return status;
+        }
+    };
+
+    if let Ok(credentials) = cache.credentials() {
+        status.expiration = Some(credentials.expiration);
+        if credentials.expiration - refresh_before_expiry > now {
+            status.next_refresh = credentials.expiration - refresh_before_expiry;
+            return status;
+        }
+    }
+    if let Some((error, retry_at)) = failures.get(name) {
+        if *retry_at > now {
+            status.error = Some(error.clone());
+            status.next_refresh = *retry_at;
+            return status;
+        }
+    }
+
+    let _lock = crate::lock_cache_entry(&cache, &role_info).await;
+    if let Ok(credentials) = cache.credentials() {
+        if credentials.expiration - refresh_before_expiry > Utc::now() {
+            debug!("Credentials for profile {name} were refreshed by another process");
+            failures.remove(name);
+            status.expiration = Some(credentials.expiration);
+            status.next_refresh = credentials.expiration - refresh_before_expiry;
+            return status;
+        }
+    }
+    info!("Refreshing credentials for profile {name}");
+    let result = match assume::acquire_credentials(&role_info, false).await {
+        Ok(credentials) => cache
+            .store_credentials(&credentials)
+            .map(|_| credentials)
+            .map_err(|error| error.to_string()),
+        Err(error) => Err(error.to_string()),
+    };
+    match result {
+        Ok(credentials) => {
+            failures.remove(name);
+            status.expiration = Some(credentials.expiration);
+            status.next_refresh = credentials.expiration - refresh_before_expiry;
+        }
+        Err(error) => {
+            warn!("Failed to refresh credentials for profile {name}: {error}");
+            failures.insert(name.to_string(), (error.clone(), status.next_refresh));
+            status.error = Some(error);
+        }
+    }
+    status
+}
+
+/// Keep the credentials of the profiles marked `keep_warm` fresh in the cache until stopped.
+pub async fn run(daemon_args: &DaemonArgs) -> Result<()> {
+    std::fs::create_dir_all(directory()?)?;
+    // Held until the daemon exits, when the operating system releases the lock
+    let Some(_pid_lock) = lock_pid_file()? else {
+        return Err(match read_status()? {
+            Some(status) => DaemonError::AlreadyRunning(status.pid),
+            None => DaemonError::AlreadyStarting,
+        });
+    };
+    if Config::load()?.cache_settings()?.store == StoreKind::Memory {
+        warn!("The cache store is 'memory', so other processes can not use the credentials kept fresh by the daemon");
+    }
+    let default_refresh_before_expiry = daemon_args
+        .refresh_before_expiry
+        .unwrap_or(DEFAULT_KEEP_WARM_REFRESH_BEFORE_EXPIRY);
+    let started = Utc::now();
+    // Written right away, so that the daemon can be stopped before the first check is done
+    write_status(&DaemonStatus {
+        pid: std::process::id(),
+        started,
+        updated: started,
+        profiles: Vec::new(),
+    })?;
+    let mut failures = HashMap::new();
+    info!("Daemon started with process ID {}", std::process::id());
+
+    loop {
+        // The config is read on every check, so that changes apply without a restart
+        let mut profiles = Vec::new();
+        match Config::load() {
+            Ok(config) => {
+                for name in config.profile_names() {
+                    let keep_warm = config
+                        .profile(&name)
+                        .map(|profile| profile.keep_warm.unwrap_or(false))
+                        .unwrap_or(false);
+                    if keep_warm {
+                        profiles.push(
+                            refresh_profile(&name, &config, default_refresh_before_expiry, &mut failures).await,
+                        );
+                    }
+                }
+            }
+            Err(error) => warn!("Failed to load the config file: {error}"),
+        }
+        write_status(&DaemonStatus {
+            pid: std::process::id(),
+            started,
+            updated: Utc::now(),
+            profiles: profiles.clone(),
+        })?;
+
+        let next_check = match profiles.iter().map(|profile| profile.next_refresh).min() {
+            Some(next_refresh) => (next_refresh - Utc::now()).to_std().unwrap_or_default(),
+            None => Duration::from_secs(DAEMON_CHECK_INTERVAL),
+        }
+        .clamp(Duration::from_secs(1), Duration::from_secs(DAEMON_CHECK_INTERVAL));
+        debug!("Next check in {} seconds", next_check.as_secs());
+        tokio::select! {
+            _ = tokio::time::sleep(next_check) => {}
+            _ = tokio::signal::ctrl_c() => {
+                info!("Daemon stopped");
+                std::fs::remove_file(status_path()?)?;
+                return Ok(());
+            }
+        }
+    }
+}
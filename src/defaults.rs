@@ -3,7 +3,51 @@ pub const VALID_AWS_PARTITIONS: [&str; 3] = ["aws", "aws-cn", "aws-us-gov"];
 pub const DEFAULT_AWS_PARTITION: &str = "aws";
 pub const DEFAULT_REGION: &str = "eu-west-1";
 pub const DEFAULT_DURATION: i32 = 3600;
+
+// Cached credentials expiring within this many seconds are acquired again
+pub const DEFAULT_MIN_REMAINING: u64 = 300;
 pub const ENV_VARS_STYLES: [&str; 2] = ["sh", "powershell"];
+pub const VALID_ASSUME_METHODS: [&str; 2] = ["saml", "web-identity"];
+pub const DEFAULT_ASSUME_METHOD: &str = "saml";
+pub const VALID_LOGIN_MODES: [&str; 3] = ["never", "prompt", "auto"];
+pub const DEFAULT_LOGIN_MODE: &str = "auto";
+pub const DEFAULT_LOGIN_COMMAND: &str = "az";
+pub const DEFAULT_LOGIN_ARGS: [&str; 1] = ["login"];
+
+// Defaults for network timeouts and retries
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+pub const DEFAULT_REQUEST_TIMEOUT: u64 = 30;
+pub const DEFAULT_DEADLINE: u64 = 300;
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+pub const RETRY_BASE_DELAY_MS: u64 = 250;
+pub const RETRY_MAX_DELAY_MS: u64 = 8000;
 
 // Default AWS credentials JSON version
 pub const DEFAULT_CREDS_VERSION: i32 = 1;
+
+// Minimum remaining lifetime in seconds for a cached Azure AD token to be reused
+pub const OIDC_TOKEN_MIN_REMAINING: i64 = 300;
+
+// Minimum remaining lifetime in seconds for a cached SAML assertion to be reused
+pub const SAML_ASSERTION_MIN_REMAINING: i64 = 30;
+
+// Session name used for AssumeRoleWithWebIdentity
+pub const DEFAULT_ROLE_SESSION_NAME: &str = "aws-credentials-cli";
+
+// Refresh served credentials this many seconds before they expire
+pub const REFRESH_BEFORE_EXPIRY: i64 = 300;
+
+// Seconds to wait before retrying a failed refresh of served credentials
+pub const REFRESH_RETRY_DELAY: u64 = 60;
+
+// Seconds before expiration at which the daemon acquires new credentials for kept warm profiles
+pub const DEFAULT_KEEP_WARM_REFRESH_BEFORE_EXPIRY: u64 = 600;
+
+// Maximum seconds between daemon checks, so that config changes and sleep are picked up
+pub const DAEMON_CHECK_INTERVAL: u64 = 60;
+
+// Seconds to wait for another process acquiring credentials, on top of its deadline
+pub const LOCK_WAIT_MARGIN: u64 = 10;
+
+// Where cached credentials and tokens are kept
+pub const VALID_CACHE_STORES: [&str; 4] = ["files", "memory", "encrypted", "keyring"];
@@ -0,0 +1,126 @@
+use std::ffi::OsString;
+use std::process::Command;
+
+use log::{info, warn};
+
+use crate::assume::models::TemporaryAwsCredentials;
+use crate::models::RoleInfo;
+
+pub type Result<T> = std::result::Result<T, ExecError>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExecError {
+    #[error("Failed to run `{command}`: {source}")]
+    RunFailed {
+        command: String,
+        source: std::io::Error,
+    },
+}
+
+// Set in the child to the role it has credentials for. Used as prompt marker in subshells and to
+// detect nesting.
+const ROLE_ENV_VAR: &str = "AWS_CREDENTIALS_CLI_ROLE";
+
+// Variables that would make AWS tools use other credentials than the injected ones
+const CONFLICTING_ENV_VARS: [&str; 11] = [
+    "AWS_PROFILE",
+    "AWS_DEFAULT_PROFILE",
+    "AWS_SECURITY_TOKEN",
+    "AWS_CREDENTIAL_EXPIRATION",
+    "AWS_ROLE_ARN",
+    "AWS_ROLE_SESSION_NAME",
+    "AWS_WEB_IDENTITY_TOKEN_FILE",
+    "AWS_CONTAINER_CREDENTIALS_FULL_URI",
+    "AWS_CONTAINER_CREDENTIALS_RELATIVE_URI",
+    "AWS_CONTAINER_AUTHORIZATION_TOKEN",
+    "AWS_CONTAINER_AUTHORIZATION_TOKEN_FILE",
+];
+
+/// The environment variables giving the credentials to the child process.
+fn credentials_env(credentials: &TemporaryAwsCredentials, role_info: &RoleInfo) -> Vec<(&'static str, String)> {
+    vec![
+        ("AWS_ACCESS_KEY_ID", credentials.access_key_id.clone()),
+        ("AWS_SECRET_ACCESS_KEY", credentials.secret_access_key.clone()),
+        ("AWS_SESSION_TOKEN", credentials.session_token.clone()),
+        ("AWS_REGION", role_info.region.clone()),
+        ("AWS_DEFAULT_REGION", role_info.region.clone()),
+        ("AWS_CREDENTIAL_EXPIRATION", credentials.expiration.to_rfc3339()),
+        (ROLE_ENV_VAR, role_info.role_arn()),
+    ]
+}
+
+/// The user's shell and the environment variables that add a prompt marker to it.
+fn subshell(role_info: &RoleInfo) -> (OsString, Vec<(&'static str, String)>) {
+    let marker = format!("(aws:{}/{})", role_info.account_id, role_info.role_name);
+    if cfg!(target_os = "windows") {
+        let shell = std::env::var_os("COMSPEC").unwrap_or_else(|| "cmd.exe".into());
+        (shell, vec![("PROMPT", format!("{marker} $P$G"))])
+    } else {
+        let shell = std::env::var_os("SHELL").unwrap_or_else(|| "/bin/sh".into());
+        // Shells whose startup files set the prompt ignore this. They can show the value of
+        // `AWS_CREDENTIALS_CLI_ROLE` in the prompt instead.
+        let prompt = std::env::var("PS1").unwrap_or_else(|_| String::from("$ "));
+        (shell, vec![("PS1", format!("{marker} {prompt}"))])
+    }
+}
+
+/// Run the command with the credentials in its environment, or start an interactive subshell if
+/// no command is given. Only returns on failure to start the command. Otherwise the process exits
+/// with the exit code of the command.
+pub fn exec(credentials: &TemporaryAwsCredentials, role_info: &RoleInfo, command_line: &[String]) -> Result<()> {
+    if let Ok(role) = std::env::var(ROLE_ENV_VAR) {
+        warn!("Already running with credentials for {role}. These are replaced.");
+    }
+    let mut command = match command_line.split_first() {
+        Some((program, args)) => {
+            let mut command = Command::new(program);
+            command.args(args);
+            command
+        }
+        None => {
+            let (shell, prompt_env) = subshell(role_info);
+            eprintln!(
+                "Starting {} with credentials for {}. Exit the shell to return.",
+                shell.to_string_lossy(),
+                role_info.role_arn()
+            );
+            let mut command = Command::new(shell);
+            command.envs(prompt_env);
+            command
+        }
+    };
+    for name in CONFLICTING_ENV_VARS {
+        command.env_remove(name);
+    }
+    command.envs(credentials_env(credentials, role_info));
+
+    let command_display = if command_line.is_empty() {
+        command.get_program().to_string_lossy().to_string()
+    } else {
+        command_line.join(" ")
+    };
+    info!("Running {command_display}");
+    let run_failed = |source| ExecError::RunFailed {
+        command: command_display.clone(),
+        source,
+    };
+    run(command).map_err(run_failed)
+}
+
+// Replacing this process with the command passes signals and the exit code on unchanged
+#[cfg(unix)]
+fn run(mut command: Command) -> std::io::Result<()> {
+    use std::os::unix::process::CommandExt;
+    Err(command.exec())
+}
+
+// Ctrl-C is sent to all processes in the console, so the command gets it without forwarding. This
+// process ignores it and waits for the command to exit.
+#[cfg(not(unix))]
+fn run(mut command: Command) -> std::io::Result<()> {
+    tokio::spawn(async {
+        while tokio::signal::ctrl_c().await.is_ok() {}
+    });
+    let status = command.status()?;
+    std::process::exit(status.code().unwrap_or(1));
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{SecondsFormat, Utc};
+use log::info;
+use serde::Serialize;
+
+use crate::models::RoleInfo;
+use crate::serve::{bind, print_env_vars, random_token, serve_http, CredentialsProvider, Request, Response, Result};
+use crate::EnvVarsStyle;
+
+const TOKEN_PATH: &str = "/latest/api/token";
+const TOKEN_HEADER: &str = "x-aws-ec2-metadata-token";
+const TOKEN_TTL_HEADER: &str = "x-aws-ec2-metadata-token-ttl-seconds";
+const MAX_TOKEN_TTL: u64 = 21600;
+const SECURITY_CREDENTIALS_PATH: &str = "/latest/meta-data/iam/security-credentials/";
+const IAM_INFO_PATH: &str = "/latest/meta-data/iam/info";
+const REGION_PATH: &str = "/latest/meta-data/placement/region";
+
+/// Session tokens handed out by the IMDSv2 handshake, with their expiration.
+#[derive(Default)]
+struct SessionTokens(Mutex<HashMap<String, Instant>>);
+
+impl SessionTokens {
+    fn issue(&self, ttl: Duration) -> Result<String> {
+        let token = random_token()?;
+        let mut tokens = self.0.lock().unwrap();
+        let now = Instant::now();
+        tokens.retain(|_, expiration| *expiration > now);
+        tokens.insert(token.clone(), now + ttl);
+        Ok(token)
+    }
+
+    fn is_valid(&self, token: &str) -> bool {
+        let tokens = self.0.lock().unwrap();
+        tokens
+            .get(token)
+            .is_some_and(|expiration| *expiration > Instant::now())
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SecurityCredentials {
+    code: &'static str,
+    last_updated: String,
+    #[serde(rename = "Type")]
+    credentials_type: &'static str,
+    access_key_id: String,
+    secret_access_key: String,
+    token: String,
+    expiration: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct IamInfo {
+    code: &'static str,
+    last_updated: String,
+    instance_profile_arn: String,
+    instance_profile_id: &'static str,
+}
+
+// The role name without the IAM path, as instance metadata has no paths
+fn metadata_role_name(role_info: &RoleInfo) -> &str {
+    role_info
+        .role_name
+        .rsplit('/')
+        .next()
+        .unwrap_or(&role_info.role_name)
+}
+
+fn issue_token(tokens: &SessionTokens, request: &Request) -> Response {
+    // Like the real service, refuse tokens to requests that went through a proxy
+    if request.headers.contains_key("x-forwarded-for") {
+        return Response::error(403, "Forwarded requests are not allowed");
+    }
+    let Some(ttl) = request
+        .headers
+        .get(TOKEN_TTL_HEADER)
+        .and_then(|ttl| ttl.parse::<u64>().ok())
+        .filter(|ttl| (1..=MAX_TOKEN_TTL).contains(ttl))
+    else {
+        return Response::error(400, "Missing or invalid token TTL");
+    };
+    match tokens.issue(Duration::from_secs(ttl)) {
+        Ok(token) => {
+            let mut response = Response::text(token);
+            response.headers.push((TOKEN_TTL_HEADER, ttl.to_string()));
+            response
+        }
+        Err(error) => Response::error(500, &error.to_string()),
+    }
+}
+
+async fn instance_metadata(provider: &CredentialsProvider, tokens: &SessionTokens, request: Request) -> Response {
+    if request.path == TOKEN_PATH {
+        return match request.method.as_str() {
+            "PUT" => issue_token(tokens, &request),
+            _ => Response::error(405, "Only PUT is supported"),
+        };
+    }
+    if request.method != "GET" {
+        return Response::error(405, "Only GET is supported");
+    }
+    // Only IMDSv2 is supported, so every request needs a session token
+    if !request
+        .headers
+        .get(TOKEN_HEADER)
+        .is_some_and(|token| tokens.is_valid(token))
+    {
+        return Response::error(401, "Missing or expired session token");
+    }
+
+    let role_info = provider.role_info();
+    let role_name = metadata_role_name(role_info);
+    let last_updated = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    match request.path.as_str() {
+        SECURITY_CREDENTIALS_PATH => Response::text(role_name.to_string()),
+        path if path.strip_prefix(SECURITY_CREDENTIALS_PATH) == Some(role_name) => {
+            match provider.credentials().await {
+                Ok(credentials) => Response::json(&SecurityCredentials {
+                    code: "Success",
+                    last_updated,
+                    credentials_type: "AWS-HMAC",
+                    access_key_id: credentials.access_key_id,
+                    secret_access_key: credentials.secret_access_key,
+                    token: credentials.session_token,
+                    expiration: credentials.expiration.to_rfc3339_opts(SecondsFormat::Secs, true),
+                }),
+                Err(error) => Response::error(500, &error),
+            }
+        }
+        IAM_INFO_PATH => Response::json(&IamInfo {
+            code: "Success",
+            last_updated,
+            instance_profile_arn: format!(
+                "arn:{}:iam::{}:instance-profile/{role_name}",
+                role_info.aws_partition, role_info.account_id
+            ),
+            instance_profile_id: "AIPAAWSCREDENTIALSCLI",
+        }),
+        REGION_PATH => Response::text(role_info.region.clone()),
+        _ => Response::error(404, "Not found"),
+    }
+}
+
+/// Serve the credentials like the EC2 instance metadata service (IMDSv2), until the process is
+/// stopped. Prints the environment variables that make SDKs and tools use it.
+pub async fn serve_instance_metadata(role_info: RoleInfo, address: SocketAddr, style: &EnvVarsStyle) -> Result<()> {
+    let (listener, address) = bind(address).await?;
+    let provider = Arc::new(CredentialsProvider::new(role_info));
+    let tokens = Arc::new(SessionTokens::default());
+
+    print_env_vars(
+        &[
+            ("AWS_EC2_METADATA_SERVICE_ENDPOINT", format!("http://{address}/")),
+            ("AWS_REGION", provider.role_info().region.clone()),
+            ("AWS_DEFAULT_REGION", provider.role_info().region.clone()),
+        ],
+        style,
+    );
+    info!("Serving instance metadata for {} on {address}", provider.role_info().role_arn());
+
+    tokio::spawn(provider.clone().keep_fresh());
+    serve_http(listener, move |request| {
+        let provider = provider.clone();
+        let tokens = tokens.clone();
+        async move { instance_metadata(&provider, &tokens, request).await }
+    })
+    .await;
+    Ok(())
+}
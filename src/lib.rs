@@ -1,24 +1,85 @@
 mod assume;
 mod cache;
 mod cli;
+mod config;
+mod daemon;
 mod defaults;
+mod exec;
+mod imds;
 mod models;
+mod private_files;
+mod regions;
+mod serve;
 
 use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
 
 use inquire::Confirm;
-use log::error;
 use log::{info, warn};
 
-use cache::{CachedCredentialsError, CredentialsCache};
-use defaults::{DEFAULT_CREDS_VERSION, DEFAULT_REGION};
-use models::{RoleInfo, RoleInfoBuilder};
+use cache::{CacheLock, CachedCredentialsError, CredentialsCache, EntryFilter, Removal, StoreKind};
+use config::{Config, Profile};
+use defaults::{
+    DEFAULT_ASSUME_METHOD, DEFAULT_AWS_PARTITION, DEFAULT_CONNECT_TIMEOUT, DEFAULT_CREDS_VERSION,
+    DEFAULT_DEADLINE, DEFAULT_DURATION, DEFAULT_LOGIN_ARGS, DEFAULT_LOGIN_COMMAND,
+    DEFAULT_LOGIN_MODE, DEFAULT_MAX_ATTEMPTS, LOCK_WAIT_MARGIN, DEFAULT_MIN_REMAINING, DEFAULT_REGION, DEFAULT_REQUEST_TIMEOUT,
+};
+use models::{
+    normalize_role_name, AssumeMethod, AzureAccount, LoginMode, LoginSettingsBuilder,
+    NetworkSettingsBuilder, RoleInfo, RoleInfoBuilder, StsEndpoint,
+};
 
 use clap::{Command, CommandFactory, Parser};
 
-use cli::{CacheCommands, Cli, Commands, OutputAsCommands};
+use cli::{CacheCommands, Cli, Commands, DaemonCommands, OutputAsCommands, RoleArgs};
 
-use crate::assume::models::OutputFormat;
+use crate::assume::console::{self, ConsoleOptions};
+use crate::assume::models::{OutputFormat, TemporaryAwsCredentials};
+
+/// The filter for `cache clear`. The account and role given on the command line take precedence
+/// over the ones of the profile.
+fn entry_filter(
+    config: &Config,
+    account: Option<String>,
+    role: Option<String>,
+    profile_name: Option<String>,
+    expired: bool,
+) -> Result<EntryFilter, Box<dyn Error>> {
+    let (profile_account, profile_role) = match profile_name {
+        Some(profile_name) => {
+            let profile = config.profile(&profile_name)?;
+            match profile.role_arn {
+                Some(role_arn) => (Some(role_arn.account_id), Some(role_arn.role_name)),
+                None if profile.account.is_some() || profile.role.is_some() => (profile.account, profile.role),
+                None => return Err(format!("Profile '{profile_name}' has no account or role").into()),
+            }
+        }
+        None => (None, None),
+    };
+    Ok(EntryFilter {
+        account_id: account.or(profile_account),
+        role_name: role.or(profile_role).as_deref().map(normalize_role_name).transpose()?,
+        expired,
+    })
+}
+
+/// Show what is about to be removed, and ask whether to go ahead unless `yes` is given.
+fn confirm_removal(removal: &Removal, store: &dyn cache::CredentialStore, yes: bool) -> bool {
+    if removal.is_empty() {
+        println!("No matching cache entries");
+        return false;
+    }
+    if yes {
+        return true;
+    }
+    eprintln!("About to delete:\n{}", removal.describe(store));
+    Confirm::new("Delete these cache entries?")
+        .with_default(false)
+        .with_placeholder("y/yes or n/no")
+        .prompt()
+        .unwrap_or(false)
+}
 
 fn print_completions<G, W>(gen: G, cmd: &mut Command, output: &mut W)
 where
@@ -28,19 +89,293 @@ where
     clap_complete::generate(gen, cmd, cmd.get_name().to_string(), output);
 }
 
+/// Build the role info from the command line options, falling back to the values in the selected
+/// profile and then to the defaults.
+fn role_info(role_args: RoleArgs) -> Result<RoleInfo, Box<dyn Error>> {
+    let config = Config::load()?;
+    let profile = match &role_args.profile_name {
+        Some(profile_name) => {
+            info!("Using profile {profile_name}");
+            config.profile(profile_name)?
+        }
+        None => Profile::default(),
+    };
+    let mut cache_settings = config.cache_settings()?;
+    if role_args.no_cache {
+        cache_settings.store = StoreKind::Memory;
+    }
+
+    // A role ARN on the command line takes precedence over anything in the profile, and an
+    // account or role on the command line over a role ARN in the profile
+    let role_arn = match (role_args.role_arn, &role_args.account, &role_args.role) {
+        (Some(role_arn), _, _) => Some(role_arn),
+        (None, None, None) => profile.role_arn,
+        _ => None,
+    };
+    let (account, role, arn_partition) = match role_arn {
+        Some(role_arn) => (role_arn.account_id, role_arn.role_name, Some(role_arn.aws_partition)),
+        None => {
+            let account = role_args
+                .account
+                .or(profile.account)
+                .ok_or("No account given on the command line or in the profile")?;
+            let role = role_args
+                .role
+                .or(profile.role)
+                .ok_or("No role given on the command line or in the profile")?;
+            (account, normalize_role_name(&role)?, None)
+        }
+    };
+    let sts_endpoint = StsEndpoint {
+        endpoint_url: role_args.sts_endpoint_url.or(profile.sts_endpoint_url),
+        use_fips: role_args.use_fips_endpoint || profile.use_fips_endpoint.unwrap_or(false),
+        use_dual_stack: role_args.use_dualstack_endpoint
+            || profile.use_dualstack_endpoint.unwrap_or(false),
+    };
+    let region = role_args.region.or(profile.region);
+    let aws_partition = role_args.aws_partition.or(profile.aws_partition);
+    if let (Some(arn_partition), Some(aws_partition)) = (&arn_partition, &aws_partition) {
+        if arn_partition != aws_partition {
+            return Err(format!(
+                "The role ARN is in the '{arn_partition}' partition, but '{aws_partition}' is configured"
+            )
+            .into());
+        }
+    }
+    let aws_partition = match (arn_partition.or(aws_partition), &region) {
+        (Some(aws_partition), _) => aws_partition,
+        (None, Some(region)) => {
+            let aws_partition = regions::partition_for_region(region);
+            info!("Using partition {aws_partition} for region {region}");
+            aws_partition.to_string()
+        }
+        (None, None) => DEFAULT_AWS_PARTITION.to_string(),
+    };
+    let region = match region {
+        Some(region) => region,
+        None => regions::default_region(&aws_partition)?.to_string(),
+    };
+    // Custom endpoints, e.g. LocalStack, may accept any region
+    if sts_endpoint.endpoint_url.is_none() {
+        regions::validate_region(&aws_partition, &region)?;
+    }
+    let duration = role_args
+        .duration
+        .or(profile.duration)
+        .unwrap_or(DEFAULT_DURATION);
+    let assume_method = match role_args
+        .assume_method
+        .or(profile.assume_method)
+        .as_deref()
+        .unwrap_or(DEFAULT_ASSUME_METHOD)
+    {
+        "saml" => AssumeMethod::Saml,
+        "web-identity" => AssumeMethod::WebIdentity,
+        assume_method => return Err(format!("Unsupported assume method: {assume_method}").into()),
+    };
+    let login_mode = match role_args
+        .login
+        .or(profile.login)
+        .as_deref()
+        .unwrap_or(DEFAULT_LOGIN_MODE)
+    {
+        "never" => LoginMode::Never,
+        "prompt" => LoginMode::Prompt,
+        "auto" => LoginMode::Auto,
+        login => return Err(format!("Unsupported login mode: {login}").into()),
+    };
+    let login_settings = LoginSettingsBuilder::default()
+        .mode(login_mode)
+        .command(
+            role_args
+                .login_command
+                .or(profile.login_command)
+                .unwrap_or(DEFAULT_LOGIN_COMMAND.to_string()),
+        )
+        .args(
+            role_args
+                .login_args
+                .or(profile.login_args)
+                .unwrap_or(DEFAULT_LOGIN_ARGS.map(String::from).to_vec()),
+        )
+        .use_device_code(role_args.use_device_code || profile.use_device_code.unwrap_or(false))
+        .build()?;
+    let network_settings = NetworkSettingsBuilder::default()
+        .connect_timeout(Duration::from_secs(
+            role_args
+                .connect_timeout
+                .or(profile.connect_timeout)
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+        ))
+        .request_timeout(Duration::from_secs(
+            role_args
+                .request_timeout
+                .or(profile.request_timeout)
+                .unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+        ))
+        .deadline(Duration::from_secs(
+            role_args.timeout.or(profile.timeout).unwrap_or(DEFAULT_DEADLINE),
+        ))
+        .max_attempts(
+            role_args
+                .max_attempts
+                .or(profile.max_attempts)
+                .unwrap_or(DEFAULT_MAX_ATTEMPTS)
+                .max(1),
+        )
+        .proxy(role_args.proxy.or(profile.proxy))
+        .ca_bundle(role_args.ca_bundle.or(profile.ca_bundle))
+        .build()?;
+    let azure_account = AzureAccount {
+        tenant_id: role_args.tenant.or(profile.tenant_id),
+        username: role_args.azure_username.or(profile.azure_username),
+    };
+
+    let min_remaining = role_args
+        .min_remaining
+        .or(profile.min_remaining)
+        .unwrap_or(Duration::from_secs(DEFAULT_MIN_REMAINING));
+    if min_remaining.as_secs() >= duration as u64 {
+        warn!(
+            "The minimum remaining lifetime of {} seconds is not shorter than the session duration of {duration} seconds, so cached credentials are never used",
+            min_remaining.as_secs()
+        );
+    }
+
+    info!("Using duration {duration}");
+    info!("Using region {region}");
+    if let Some(tenant_id) = &azure_account.tenant_id {
+        info!("Using Azure AD tenant {tenant_id}");
+    }
+
+    let role_info = RoleInfoBuilder::default()
+        .aws_partition(aws_partition)
+        .role_name(role)
+        .account_id(account)
+        .region(region)
+        .duration(duration)
+        .assume_method(assume_method)
+        .azure_account(azure_account)
+        .cache_saml_assertion(
+            role_args.cache_saml_assertion || profile.cache_saml_assertion.unwrap_or(false),
+        )
+        .login(login_settings)
+        .network(network_settings)
+        .sts_endpoint(sts_endpoint)
+        .min_remaining(min_remaining)
+        .cache_settings(cache_settings)
+        .build()?;
+    Ok(role_info)
+}
+
+/// The cached credentials for the role, if they are valid for at least the minimum remaining
+/// lifetime.
+fn cached_credentials(credentials_cache: &CredentialsCache, role_info: &RoleInfo) -> Option<TemporaryAwsCredentials> {
+    match credentials_cache.credentials() {
+        Ok(credentials) if credentials.is_fresh(role_info.min_remaining) => Some(credentials),
+        Ok(credentials) => {
+            info!(
+                "Cached credentials expire at {}, within {} seconds",
+                credentials.expiration,
+                role_info.min_remaining.as_secs()
+            );
+            None
+        }
+        Err(error) => {
+            match error {
+                CachedCredentialsError::NotFound(_) => {
+                    info!("No cached credentials found")
+                }
+                CachedCredentialsError::JsonError(err) => {
+                    warn!("JSON data error: {err}. Ignoring cache.")
+                }
+                CachedCredentialsError::UnsupportedPlatform => {
+                    warn!("Can not cache credentials on this platform")
+                }
+                err @ (CachedCredentialsError::Expired(_) | CachedCredentialsError::UnsupportedFormat { .. }) => {
+                    info!("{err}")
+                }
+                err => {
+                    warn!("{err}. Ignoring cache.")
+                }
+            };
+            None
+        }
+    }
+}
+
+/// Lock the cache entry for acquiring credentials. Waits for another process acquiring them for
+/// up to its deadline. Credentials are acquired without the lock if it can not be had.
+async fn lock_cache_entry(credentials_cache: &CredentialsCache, role_info: &RoleInfo) -> Option<CacheLock> {
+    let timeout = role_info.network.deadline + Duration::from_secs(LOCK_WAIT_MARGIN);
+    match credentials_cache.lock(timeout).await {
+        Ok(Some(lock)) => Some(lock),
+        Ok(None) => {
+            warn!(
+                "Another process did not finish acquiring the credentials within {} seconds. Acquiring them anyway",
+                timeout.as_secs()
+            );
+            None
+        }
+        Err(error) => {
+            warn!("Failed to lock the cache entry: {error}. Acquiring the credentials without lock");
+            None
+        }
+    }
+}
+
+/// Get the credentials for the role from the cache, or acquire and cache them if there are no
+/// valid cached credentials or `force` is set.
+async fn credentials(role_info: &RoleInfo, force: bool) -> Result<TemporaryAwsCredentials, Box<dyn Error>> {
+    let credentials_cache = CredentialsCache::new(role_info)?;
+    if !force {
+        info!("Attempting to fetch credentials from cache");
+        if let Some(credentials) = cached_credentials(&credentials_cache, role_info) {
+            return Ok(credentials);
+        }
+    }
+
+    // Processes started in parallel, e.g. by `credential_process`, wait for the first one to
+    // acquire the credentials and then use the ones it cached
+    let _lock = lock_cache_entry(&credentials_cache, role_info).await;
+    if !force {
+        if let Some(credentials) = cached_credentials(&credentials_cache, role_info) {
+            info!("Using the credentials cached by another process");
+            return Ok(credentials);
+        }
+    }
+    info!("Acquiring credentials");
+    let credentials = assume::acquire_credentials(role_info, force).await?;
+    credentials_cache.store_credentials(&credentials)?;
+    Ok(credentials)
+}
+
 #[derive(Clone, Debug)]
 enum EnvVarsStyle {
     Sh,
     PowerShell,
 }
 
+fn env_vars_style(style: &str) -> Result<EnvVarsStyle, Box<dyn Error>> {
+    match style {
+        "sh" => Ok(EnvVarsStyle::Sh),
+        "powershell" => Ok(EnvVarsStyle::PowerShell),
+        _ => Err(format!("Unsupported shell type: {style}").into()),
+    }
+}
+
 pub async fn run() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
     env_logger::Builder::new()
         .filter_level(cli.verbose.log_level_filter())
         .init();
     match cli.command {
-        Commands::Cache { command } => {
+        Commands::Cache { store, command } => {
+            let config = Config::load()?;
+            let mut cache_settings = config.cache_settings()?;
+            if let Some(store) = store {
+                cache_settings.store = store.parse()?;
+            }
             let cache_command = command.unwrap_or(CacheCommands::Path);
             match cache_command {
                 CacheCommands::Path => {
@@ -54,32 +389,40 @@ pub async fn run() -> Result<(), Box<dyn Error>> {
                     }
                     return Ok(());
                 }
-                CacheCommands::Clear { yes } => {
-                    let do_delete = if yes {
-                        true
+                CacheCommands::List { json } => {
+                    let store = cache::open_store(&cache_settings)?;
+                    let listings = cache::list_credentials(store.as_ref(), &config.account_aliases())?;
+                    if json {
+                        println!("{}", serde_json::to_string_pretty(&listings)?);
                     } else {
-                        Confirm::new("About to delete cached credentials. Are you sure?")
-                            .with_default(false)
-                            .with_help_message(
-                                "This will delete ALL cached credentials in the cache directory.",
-                            )
-                            .with_placeholder("y/yes or n/no")
-                            .prompt()
-                            .unwrap_or(false)
-                    };
-                    if do_delete {
-                        info!("Deleting all cached credentials.");
-                        CredentialsCache::remove_all_cached_files()?;
+                        cache::print_table(&listings);
+                    }
+                }
+                CacheCommands::Clear {
+                    yes,
+                    account,
+                    role,
+                    profile_name,
+                    expired,
+                } => {
+                    let filter = entry_filter(&config, account, role, profile_name, expired)?;
+                    let store = cache::open_store(&cache_settings)?;
+                    let removal = Removal::matching(store.as_ref(), &filter)?;
+                    if confirm_removal(&removal, store.as_ref(), yes) {
+                        removal.execute(store.as_ref())?;
+                    }
+                }
+                CacheCommands::Prune { yes } => {
+                    let store = cache::open_store(&cache_settings)?;
+                    let removal = Removal::prunable(store.as_ref())?;
+                    if confirm_removal(&removal, store.as_ref(), yes) {
+                        removal.execute(store.as_ref())?;
                     }
                 }
             }
         }
         Commands::Assume {
-            aws_partition,
-            account,
-            role,
-            duration,
-            region,
+            role_args,
             force,
             output_as,
         } => {
@@ -96,63 +439,79 @@ pub async fn run() -> Result<(), Box<dyn Error>> {
                     credentials_file,
                     profile,
                 },
-                OutputAsCommands::EnvVars { style } => {
-                    OutputFormat::EnvVars(match style.as_str() {
-                        "sh" => EnvVarsStyle::Sh,
-                        "powershell" => EnvVarsStyle::PowerShell,
-                        _ => {
-                            error!("Unsupported shell type: {style}");
-                            return Ok(());
-                        }
-                    })
-                }
-            };
-            info!("Using duration {duration}");
-            info!("Using region {region}");
-
-            let role_info = RoleInfoBuilder::default()
-                .aws_partition(aws_partition)
-                .role_name(role)
-                .account_id(account)
-                .region(region)
-                .duration(duration)
-                .build()?;
-
-            let credentials_cache = CredentialsCache::new(&role_info)?;
-            let cached_credentials = if force {
-                None
-            } else {
-                info!("Attempting to fetch credentials from cache");
-                match credentials_cache.credentials() {
-                    Ok(credentials) => Some(credentials),
-                    Err(error) => {
-                        match error {
-                            CachedCredentialsError::JsonError(err) => {
-                                warn!("JSON data error: {err}. Ignoring cache.")
-                            }
-                            CachedCredentialsError::FileSystemError(_) => {
-                                info!("Cache file not found")
-                            }
-                            CachedCredentialsError::UnsupportedPlatform => {
-                                warn!("Can not cache credentials on this platform")
-                            }
-                        };
-                        None
-                    }
-                }
+                OutputAsCommands::EnvVars { style } => OutputFormat::EnvVars(env_vars_style(&style)?),
             };
+            let role_info = role_info(role_args)?;
 
-            let credentials = match cached_credentials {
-                Some(credentials) => credentials,
-                None => {
-                    info!("Acquiring credentials");
-                    let new_credentials = assume::acquire_credentials(&role_info).await?;
-                    credentials_cache.store_credentials(&new_credentials)?;
-                    new_credentials
-                }
-            };
+            let credentials = credentials(&role_info, force).await?;
             credentials.output_as(&output_format)?;
         }
+        Commands::Console {
+            role_args,
+            force,
+            destination,
+            issuer,
+            session_duration,
+            open,
+            logout,
+        } => {
+            let role_info = role_info(role_args)?;
+            let url = if logout {
+                console::logout_url(&role_info.aws_partition)?
+            } else {
+                let credentials = credentials(&role_info, force).await?;
+                let options = ConsoleOptions {
+                    destination,
+                    issuer,
+                    session_duration,
+                };
+                info!("Getting a console sign-in token");
+                console::sign_in_url(
+                    &credentials,
+                    &role_info.aws_partition,
+                    &role_info.region,
+                    &role_info.network,
+                    &options,
+                )
+                .await?
+            };
+            if open {
+                console::open_in_browser(&url)?;
+            } else {
+                println!("{url}");
+            }
+        }
+        Commands::Exec {
+            role_args,
+            force,
+            command,
+        } => {
+            let role_info = role_info(role_args)?;
+            let credentials = credentials(&role_info, force).await?;
+            exec::exec(&credentials, &role_info, &command)?;
+        }
+        Commands::Serve {
+            role_args,
+            imds,
+            address,
+            port,
+            style,
+        } => {
+            let role_info = role_info(role_args)?;
+            let address = SocketAddr::new(address, port);
+            let style = env_vars_style(&style)?;
+            if imds {
+                imds::serve_instance_metadata(role_info, address, &style).await?;
+            } else {
+                serve::serve_container_credentials(role_info, address, &style).await?;
+            }
+        }
+        Commands::Daemon { command } => match command {
+            DaemonCommands::Start { daemon_args } => daemon::start(&daemon_args)?,
+            DaemonCommands::Run { daemon_args } => daemon::run(&daemon_args).await?,
+            DaemonCommands::Stop => daemon::stop()?,
+            DaemonCommands::Status => daemon::status()?,
+        },
         Commands::GenerateCompletions { shell, mut output } => {
             eprintln!("Generating completion file for {shell} ...");
             let mut cmd = Cli::command();
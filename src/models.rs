@@ -1,12 +1,265 @@
 use derive_builder::Builder;
 
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::cache::CacheSettings;
+use crate::defaults::{
+    DEFAULT_CONNECT_TIMEOUT, DEFAULT_DEADLINE, DEFAULT_LOGIN_ARGS, DEFAULT_LOGIN_COMMAND,
+    DEFAULT_MAX_ATTEMPTS, DEFAULT_MIN_REMAINING, DEFAULT_REQUEST_TIMEOUT, VALID_AWS_PARTITIONS,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AssumeMethod {
+    /// Exchange the Azure AD token for a SAML assertion and call `AssumeRoleWithSAML`.
+    Saml,
+    /// Pass the Azure AD token directly to `AssumeRoleWithWebIdentity`.
+    WebIdentity,
+}
+
+impl AssumeMethod {
+    /// The name used on the command line and in the config file.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AssumeMethod::Saml => "saml",
+            AssumeMethod::WebIdentity => "web-identity",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoginMode {
+    /// Never run the login command. Fail with guidance instead.
+    Never,
+    /// Ask for confirmation before running the login command.
+    Prompt,
+    /// Run the login command when running in a terminal, fail otherwise.
+    Auto,
+}
+
+#[derive(Clone, Debug, Builder)]
+pub struct LoginSettings {
+    pub mode: LoginMode,
+    /// The login command, e.g. `az`.
+    pub command: String,
+    /// The arguments passed to the login command, e.g. `login`.
+    pub args: Vec<String>,
+    #[builder(default)]
+    pub use_device_code: bool,
+}
+
+impl LoginSettings {
+    /// All arguments for the login command including the ones derived from the settings.
+    pub fn command_args(&self, azure_account: &AzureAccount) -> Vec<String> {
+        let mut args = self.args.clone();
+        if let Some(tenant_id) = &azure_account.tenant_id {
+            args.push(String::from("--tenant"));
+            args.push(tenant_id.clone());
+        }
+        if let Some(username) = &azure_account.username {
+            args.push(String::from("--username"));
+            args.push(username.clone());
+        }
+        if self.use_device_code {
+            args.push(String::from("--use-device-code"));
+        }
+        args
+    }
+
+    /// The full login command line for use in messages.
+    pub fn command_line(&self, azure_account: &AzureAccount) -> String {
+        std::iter::once(self.command.clone())
+            .chain(self.command_args(azure_account))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl Default for LoginSettings {
+    fn default() -> Self {
+        Self {
+            mode: LoginMode::Auto,
+            command: String::from(DEFAULT_LOGIN_COMMAND),
+            args: DEFAULT_LOGIN_ARGS.iter().map(|arg| arg.to_string()).collect(),
+            use_device_code: false,
+        }
+    }
+}
+
+/// Timeouts and retries for the token exchange and STS calls.
+#[derive(Clone, Debug, Builder)]
+pub struct NetworkSettings {
+    pub connect_timeout: Duration,
+    /// Timeout for a single request attempt.
+    pub request_timeout: Duration,
+    /// Deadline for acquiring credentials, including all attempts.
+    pub deadline: Duration,
+    /// Maximum number of attempts for retryable failures.
+    pub max_attempts: u32,
+    /// Explicit proxy for all requests. If not set the `HTTPS_PROXY` and `NO_PROXY` environment
+    /// variables are used.
+    #[builder(default)]
+    pub proxy: Option<String>,
+    /// PEM file with extra CA certificates to trust in addition to the platform's ones.
+    #[builder(default)]
+    pub ca_bundle: Option<PathBuf>,
+}
+
+impl NetworkSettings {
+    pub fn has_custom_transport(&self) -> bool {
+        self.proxy.is_some() || self.ca_bundle.is_some()
+    }
+
+    pub fn ca_bundle_pem(&self) -> std::io::Result<Option<Vec<u8>>> {
+        self.ca_bundle.as_ref().map(std::fs::read).transpose()
+    }
+
+    /// Environment variables making child processes, e.g. the Azure CLI, use the same proxy and CA
+    /// bundle.
+    pub fn child_process_env(&self) -> Vec<(&'static str, String)> {
+        let mut env = Vec::new();
+        if let Some(proxy) = &self.proxy {
+            env.push(("HTTPS_PROXY", proxy.clone()));
+            env.push(("HTTP_PROXY", proxy.clone()));
+        }
+        if let Some(ca_bundle) = &self.ca_bundle {
+            env.push(("REQUESTS_CA_BUNDLE", ca_bundle.display().to_string()));
+        }
+        env
+    }
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT),
+            request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT),
+            deadline: Duration::from_secs(DEFAULT_DEADLINE),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            proxy: None,
+            ca_bundle: None,
+        }
+    }
+}
+
+/// The Azure AD tenant and user to acquire tokens for. If neither is set then the currently active
+/// Azure CLI account is used.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct AzureAccount {
+    pub tenant_id: Option<String>,
+    pub username: Option<String>,
+}
+
+impl AzureAccount {
+    pub fn is_default(&self) -> bool {
+        self.tenant_id.is_none() && self.username.is_none()
+    }
+}
+
+/// How to reach STS. By default the regional endpoint for the partition is used.
+#[derive(Clone, Debug, Default)]
+pub struct StsEndpoint {
+    /// Custom endpoint, e.g. a VPC interface endpoint or a local stand-in for testing.
+    pub endpoint_url: Option<String>,
+    pub use_fips: bool,
+    pub use_dual_stack: bool,
+}
+
+/// Parse a duration given as seconds, optionally with a unit, e.g. `300`, `90s`, `10m` or `1h`.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, unit_seconds) = match value.char_indices().last() {
+        Some((index, 's')) => (&value[..index], 1),
+        Some((index, 'm')) => (&value[..index], 60),
+        Some((index, 'h')) => (&value[..index], 3600),
+        _ => (value, 1),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration '{value}'. Use e.g. '300', '90s', '10m' or '1h'"))?;
+    let seconds = number
+        .checked_mul(unit_seconds)
+        .ok_or_else(|| format!("Invalid duration '{value}'. It is too long"))?;
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Normalize a role name that may include an IAM path, e.g. `platform/deployer` or
+/// `/platform/deployer`, to the form used in role ARNs.
+pub fn normalize_role_name(role_name: &str) -> Result<String, String> {
+    let role_name = role_name.strip_prefix('/').unwrap_or(role_name);
+    if role_name.is_empty() || role_name.split('/').any(str::is_empty) {
+        return Err(format!("Invalid role name '{role_name}'"));
+    }
+    Ok(role_name.to_string())
+}
+
+/// A role ARN, e.g. `arn:aws:iam::123456789012:role/platform/deployer`. The role name includes
+/// the path, if any.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoleArn {
+    pub aws_partition: String,
+    pub account_id: String,
+    pub role_name: String,
+}
+
+impl FromStr for RoleArn {
+    type Err = String;
+
+    fn from_str(arn: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| format!("Invalid role ARN '{arn}': {reason}");
+        let parts: Vec<&str> = arn.splitn(6, ':').collect();
+        let ["arn", aws_partition, "iam", "", account_id, resource] = parts[..] else {
+            return Err(invalid("expected 'arn:<partition>:iam::<account>:role/<name>'"));
+        };
+        if !VALID_AWS_PARTITIONS.contains(&aws_partition) {
+            return Err(invalid(&format!("unknown partition '{aws_partition}'")));
+        }
+        if account_id.len() != 12 || !account_id.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid("the account must be 12 digits"));
+        }
+        let role_name = resource
+            .strip_prefix("role/")
+            .ok_or_else(|| invalid("not a role"))?;
+        // Unlike role names given on their own, the name in an ARN never starts with a `/`
+        if role_name.starts_with('/') {
+            return Err(invalid("the role name is empty"));
+        }
+        Ok(Self {
+            aws_partition: aws_partition.to_string(),
+            account_id: account_id.to_string(),
+            role_name: normalize_role_name(role_name).map_err(|e| invalid(&e))?,
+        })
+    }
+}
+
 #[derive(Debug, Builder)]
 pub struct RoleInfo {
     pub aws_partition: String,
+    /// The role name, including the IAM path if any, e.g. `platform/deployer`.
     pub role_name: String,
     pub account_id: String,
     pub region: String,
     pub duration: i32,
+    #[builder(default = "AssumeMethod::Saml")]
+    pub assume_method: AssumeMethod,
+    #[builder(default)]
+    pub azure_account: AzureAccount,
+    /// Also cache the SAML assertion on disk, so that other runs can reuse it for other roles in
+    /// the same account.
+    #[builder(default)]
+    pub cache_saml_assertion: bool,
+    #[builder(default)]
+    pub login: LoginSettings,
+    #[builder(default)]
+    pub network: NetworkSettings,
+    #[builder(default)]
+    pub sts_endpoint: StsEndpoint,
+    /// Cached credentials that expire within this time are acquired again.
+    #[builder(default = "Duration::from_secs(DEFAULT_MIN_REMAINING)")]
+    pub min_remaining: Duration,
+    #[builder(default)]
+    pub cache_settings: CacheSettings,
 }
 
 impl RoleInfo {
@@ -14,3 +267,71 @@ impl RoleInfo {
         format!("arn:{}:iam::{}:role/{}", self.aws_partition, self.account_id, self.role_name)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations_with_units() {
+        assert_eq!(parse_duration("300"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration(" 10m "), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("-1").is_err());
+        assert!(parse_duration("10d").is_err());
+        assert!(parse_duration("9999999999999999h").is_err());
+        assert!(parse_duration(&format!("{}m", u64::MAX / 60 + 1)).is_err());
+    }
+
+    #[test]
+    fn normalizes_role_names() {
+        assert_eq!(normalize_role_name("deployer"), Ok(String::from("deployer")));
+        assert_eq!(normalize_role_name("/deployer"), Ok(String::from("deployer")));
+        assert_eq!(normalize_role_name("platform/deployer"), Ok(String::from("platform/deployer")));
+        assert_eq!(normalize_role_name("/platform/ci/deployer"), Ok(String::from("platform/ci/deployer")));
+        assert!(normalize_role_name("").is_err());
+        assert!(normalize_role_name("/").is_err());
+        assert!(normalize_role_name("platform//deployer").is_err());
+        assert!(normalize_role_name("platform/").is_err());
+    }
+
+    #[test]
+    fn parses_role_arns() {
+        assert_eq!(
+            "arn:aws:iam::123456789012:role/deployer".parse(),
+            Ok(RoleArn {
+                aws_partition: String::from("aws"),
+                account_id: String::from("123456789012"),
+                role_name: String::from("deployer"),
+            })
+        );
+        let arn: RoleArn = "arn:aws-cn:iam::123456789012:role/platform/deployer".parse().unwrap();
+        assert_eq!(arn.aws_partition, "aws-cn");
+        assert_eq!(arn.role_name, "platform/deployer");
+    }
+
+    #[test]
+    fn rejects_invalid_role_arns() {
+        for arn in [
+            "arn:aws:iam::123456789012:role/platform//deployer",
+            "arn:aws:iam::123456789012:role/",
+            "arn:aws:iam::123456789012:role//deployer",
+            "arn:aws-moon:iam::123456789012:role/deployer",
+            "arn:aws:iam::12345678901:role/deployer",
+            "arn:aws:iam::1234567890123:role/deployer",
+            "arn:aws:iam::12345678901a:role/deployer",
+            "arn:aws:iam::123456789012:user/deployer",
+            "arn:aws:sts::123456789012:role/deployer",
+            "arn:aws:iam:eu-west-1:123456789012:role/deployer",
+            "deployer",
+        ] {
+            assert!(arn.parse::<RoleArn>().is_err(), "{arn} should be rejected");
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+
+/// Write the file with permissions for the owner only. The contents are written to a temporary
+/// file in the same directory, flushed to disk and renamed over the file, so that readers never
+/// see a partially written file.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    // Replace the target of a symlink, e.g. into a dotfiles repository, and not the symlink
+    let path = match fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => path.to_path_buf(),
+        Err(error) => return Err(error),
+    };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file", path.display())))?;
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let temp_path = directory.join(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    let result = write_and_rename(&temp_path, &path, &directory, contents);
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+fn write_and_rename(temp_path: &Path, path: &Path, directory: &Path, contents: &[u8]) -> io::Result<()> {
+    // A temporary file left by a crashed process with the same ID may have other permissions
+    match fs::remove_file(temp_path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+        _ => {}
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(temp_path, path)?;
+    debug!("Wrote {}", path.display());
+    sync_directory(directory);
+    Ok(())
+}
+
+// Make the rename durable. Failing to do so only risks losing the new file on a crash.
+#[cfg(unix)]
+fn sync_directory(directory: &Path) {
+    if let Err(error) = fs::File::open(directory).and_then(|directory| directory.sync_all()) {
+        debug!("Failed to sync directory {}: {error}", directory.display());
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_directory(_directory: &Path) {}
+
+/// Create the directory with permissions for the owner only, and restrict the permissions of an
+/// existing one.
+pub fn create_private_dir(path: &Path) -> io::Result<()> {
+    fs::create_dir_all(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+        if mode & 0o077 != 0 {
+            info!("Restricting permissions of {} from {mode:o} to 700", path.display());
+            fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+        }
+    }
+    Ok(())
+}
+
+/// The permissions of the file if other users can access it. Always `None` on platforms without
+/// Unix permissions.
+pub fn loose_permissions(path: &Path) -> io::Result<Option<u32>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+        Ok(Some(mode).filter(|mode| mode & 0o077 != 0))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Ok(None)
+    }
+}
+
+/// Restrict the permissions of the file to the owner. Fails for files of other users.
+pub fn restrict_permissions(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+    }
+    Ok(())
+}
@@ -0,0 +1,203 @@
+use log::warn;
+
+use crate::defaults::DEFAULT_REGION;
+
+pub type Result<T> = std::result::Result<T, RegionError>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RegionError {
+    #[error("Region '{region}' is in the '{actual}' partition, not '{partition}'. Use '--aws-partition {actual}' or a region in '{partition}', e.g. '{default_region}'.")]
+    WrongPartition {
+        region: String,
+        partition: String,
+        actual: String,
+        default_region: String,
+    },
+    #[error("Unknown region '{region}' in the '{partition}' partition.{suggestion}")]
+    UnknownRegion {
+        region: String,
+        partition: String,
+        suggestion: String,
+    },
+    #[error("Unknown partition '{0}'")]
+    UnknownPartition(String),
+}
+
+struct Partition {
+    name: &'static str,
+    default_region: &'static str,
+    regions: &'static [&'static str],
+}
+
+const PARTITIONS: [Partition; 3] = [
+    Partition {
+        name: "aws",
+        default_region: DEFAULT_REGION,
+        regions: &[
+            "af-south-1",
+            "ap-east-1",
+            "ap-east-2",
+            "ap-northeast-1",
+            "ap-northeast-2",
+            "ap-northeast-3",
+            "ap-south-1",
+            "ap-south-2",
+            "ap-southeast-1",
+            "ap-southeast-2",
+            "ap-southeast-3",
+            "ap-southeast-4",
+            "ap-southeast-5",
+            "ap-southeast-6",
+            "ap-southeast-7",
+            "ca-central-1",
+            "ca-west-1",
+            "eu-central-1",
+            "eu-central-2",
+            "eu-north-1",
+            "eu-south-1",
+            "eu-south-2",
+            "eu-west-1",
+            "eu-west-2",
+            "eu-west-3",
+            "il-central-1",
+            "me-central-1",
+            "me-south-1",
+            "mx-central-1",
+            "sa-east-1",
+            "us-east-1",
+            "us-east-2",
+            "us-west-1",
+            "us-west-2",
+        ],
+    },
+    Partition {
+        name: "aws-cn",
+        default_region: "cn-north-1",
+        regions: &["cn-north-1", "cn-northwest-1"],
+    },
+    Partition {
+        name: "aws-us-gov",
+        default_region: "us-gov-west-1",
+        regions: &["us-gov-east-1", "us-gov-west-1"],
+    },
+];
+
+fn partition(name: &str) -> Result<&'static Partition> {
+    PARTITIONS
+        .iter()
+        .find(|partition| partition.name == name)
+        .ok_or_else(|| RegionError::UnknownPartition(name.to_string()))
+}
+
+pub fn default_region(partition_name: &str) -> Result<&'static str> {
+    Ok(partition(partition_name)?.default_region)
+}
+
+/// The partition a region belongs to. Falls back to the region name prefix for regions that are
+/// not known yet.
+pub fn partition_for_region(region: &str) -> &'static str {
+    if let Some(partition) = PARTITIONS
+        .iter()
+        .find(|partition| partition.regions.contains(&region))
+    {
+        return partition.name;
+    }
+    if region.starts_with("cn-") {
+        "aws-cn"
+    } else if region.starts_with("us-gov-") {
+        "aws-us-gov"
+    } else {
+        "aws"
+    }
+}
+
+// Region names look like `eu-west-1` or `us-gov-east-1`
+fn is_well_formed(region: &str) -> bool {
+    let parts: Vec<&str> = region.split('-').collect();
+    let (number, names) = parts.split_last().expect("split returns at least one part");
+    names.len() >= 2
+        && names
+            .iter()
+            .all(|name| !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase()))
+        && !number.is_empty()
+        && number.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Check that the region is a known region in the partition. Well-formed regions that are not
+/// known yet are accepted with a warning, as AWS adds regions regularly.
+pub fn validate_region(partition_name: &str, region: &str) -> Result<()> {
+    let partition = partition(partition_name)?;
+    if partition.regions.contains(&region) {
+        return Ok(());
+    }
+    if let Some(actual) = PARTITIONS
+        .iter()
+        .find(|other| other.regions.contains(&region))
+    {
+        return Err(RegionError::WrongPartition {
+            region: region.to_string(),
+            partition: partition.name.to_string(),
+            actual: actual.name.to_string(),
+            default_region: partition.default_region.to_string(),
+        });
+    }
+    let suggestion = partition
+        .regions
+        .iter()
+        .map(|candidate| (edit_distance(region, candidate), candidate))
+        .min()
+        .filter(|(distance, _)| *distance <= 3)
+        .map(|(_, candidate)| format!(" Did you mean '{candidate}'?"))
+        .unwrap_or_default();
+    if is_well_formed(region) {
+        warn!("Unknown region '{region}' in the '{}' partition.{suggestion}", partition.name);
+        return Ok(());
+    }
+    Err(RegionError::UnknownRegion {
+        region: region.to_string(),
+        partition: partition.name.to_string(),
+        suggestion,
+    })
+}
+
+/// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_unknown_well_formed_regions() {
+        assert!(validate_region("aws", "eu-west-1").is_ok());
+        assert!(validate_region("aws", "xx-newregion-7").is_ok());
+    }
+
+    #[test]
+    fn rejects_regions_of_other_partitions_and_malformed_regions() {
+        assert!(matches!(
+            validate_region("aws", "cn-north-1"),
+            Err(RegionError::WrongPartition { .. })
+        ));
+        assert!(matches!(
+            validate_region("aws", "euwest1"),
+            Err(RegionError::UnknownRegion { .. })
+        ));
+        assert!(matches!(
+            validate_region("aws", "eu-west-"),
+            Err(RegionError::UnknownRegion { .. })
+        ));
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use base64::Engine;
+use chrono::{SecondsFormat, Utc};
+use log::{debug, info, warn};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+use crate::assume::models::TemporaryAwsCredentials;
+use crate::defaults::{REFRESH_BEFORE_EXPIRY, REFRESH_RETRY_DELAY};
+use crate::models::RoleInfo;
+use crate::EnvVarsStyle;
+
+pub type Result<T> = std::result::Result<T, ServeError>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ServeError {
+    #[error("Failed to listen on {address}: {source}")]
+    ListenFailed {
+        address: SocketAddr,
+        source: std::io::Error,
+    },
+    #[error("Failed to generate the authorization token: {0}")]
+    TokenGenerationFailed(getrandom::Error),
+}
+
+const CREDENTIALS_PATH: &str = "/credentials";
+// Requests are a request line and a few headers, so anything larger is not a client of ours
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Credentials for a role that are kept fresh for serving. They are taken from the cache, and
+/// acquired again when they are about to expire.
+pub struct CredentialsProvider {
+    role_info: RoleInfo,
+    credentials: Mutex<Option<TemporaryAwsCredentials>>,
+    // Held while acquiring credentials, so that they are acquired only once at a time
+    refreshing: Mutex<()>,
+}
+
+impl CredentialsProvider {
+    pub fn new(role_info: RoleInfo) -> Self {
+        Self {
+            role_info,
+            credentials: Mutex::new(None),
+            refreshing: Mutex::new(()),
+        }
+    }
+
+    pub fn role_info(&self) -> &RoleInfo {
+        &self.role_info
+    }
+
+    async fn unexpired(&self, valid_for: chrono::Duration) -> Option<TemporaryAwsCredentials> {
+        self.credentials
+            .lock()
+            .await
+            .as_ref()
+            .filter(|credentials| credentials.expiration - valid_for > Utc::now())
+            .cloned()
+    }
+
+    /// The current credentials as long as they have not expired, so that requests do not wait for
+    /// refreshing them, which `keep_fresh` does. New credentials are only acquired when there are
+    /// none.
+    pub async fn credentials(&self) -> std::result::Result<TemporaryAwsCredentials, String> {
+        if let Some(credentials) = self.unexpired(chrono::Duration::zero()).await {
+            return Ok(credentials);
+        }
+        let _refreshing = self.refreshing.lock().await;
+        // Acquired by another request while waiting
+        if let Some(credentials) = self.unexpired(chrono::Duration::zero()).await {
+            return Ok(credentials);
+        }
+        self.refresh().await
+    }
+
+    /// Credentials that are valid for at least `REFRESH_BEFORE_EXPIRY` seconds, acquiring them if
+    /// the current ones are not.
+    async fn refresh_if_needed(&self) -> std::result::Result<TemporaryAwsCredentials, String> {
+        let refresh_before_expiry = chrono::Duration::seconds(REFRESH_BEFORE_EXPIRY);
+        let _refreshing = self.refreshing.lock().await;
+        if let Some(credentials) = self.unexpired(refresh_before_expiry).await {
+            return Ok(credentials);
+        }
+        self.refresh().await
+    }
+
+    async fn refresh(&self) -> std::result::Result<TemporaryAwsCredentials, String> {
+        let refresh_before_expiry = chrono::Duration::seconds(REFRESH_BEFORE_EXPIRY);
+        // Another process may have refreshed the cached credentials already
+        let mut credentials = crate::credentials(&self.role_info, false)
+            .await
+            .map_err(|err| err.to_string())?;
+        if credentials.expiration - refresh_before_expiry <= Utc::now() {
+            info!("Credentials expire at {}. Refreshing them", credentials.expiration);
+            credentials = crate::credentials(&self.role_info, true)
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+        *self.credentials.lock().await = Some(credentials.clone());
+        Ok(credentials)
+    }
+
+    /// Refresh the credentials shortly before they expire, so that requests do not have to wait
+    /// for it. Failed refreshes are retried after `REFRESH_RETRY_DELAY` seconds, while the current
+    /// credentials are served until they expire.
+    pub async fn keep_fresh(self: Arc<Self>) {
+        loop {
+            let delay = match self.refresh_if_needed().await {
+                Ok(credentials) => {
+                    let refresh_at = credentials.expiration - chrono::Duration::seconds(REFRESH_BEFORE_EXPIRY);
+                    (refresh_at - Utc::now()).to_std().unwrap_or_default()
+                }
+                Err(error) => {
+                    match self.unexpired(chrono::Duration::zero()).await {
+                        Some(credentials) => warn!(
+                            "Failed to refresh credentials: {error}. Serving the current credentials until they expire at {}. Retrying in {REFRESH_RETRY_DELAY} seconds",
+                            credentials.expiration
+                        ),
+                        None => warn!("Failed to get credentials: {error}. Retrying in {REFRESH_RETRY_DELAY} seconds"),
+                    }
+                    Duration::from_secs(REFRESH_RETRY_DELAY)
+                }
+            };
+            debug!("Next credentials refresh in {} seconds", delay.as_secs());
+            tokio::time::sleep(delay.max(Duration::from_secs(1))).await;
+        }
+    }
+}
+
+/// A minimal HTTP request, as sent by the SDK credential providers.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Header names are lowercase.
+    pub headers: HashMap<String, String>,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json<T: Serialize>(value: &T) -> Self {
+        Self {
+            status: 200,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: serde_json::to_string(value).unwrap_or_default(),
+        }
+    }
+
+    pub fn text(body: String) -> Self {
+        Self {
+            status: 200,
+            content_type: "text/plain",
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: serde_json::json!({ "code": reason_phrase(status), "message": message }).to_string(),
+        }
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+async fn read_request(stream: &mut TcpStream) -> Option<Request> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 1024];
+    while !buffer.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut chunk).await.ok()?;
+        if read == 0 || buffer.len() + read > MAX_REQUEST_SIZE {
+            return None;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+    let head = String::from_utf8_lossy(&buffer);
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    Some(Request {
+        method,
+        path,
+        headers,
+    })
+}
+
+async fn write_response(stream: &mut TcpStream, response: &Response) -> std::io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        reason_phrase(response.status),
+        response.content_type,
+        response.body.len(),
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Answer HTTP requests on the listener with `handler`, one connection per request.
+pub async fn serve_http<H, Fut>(listener: TcpListener, handler: H)
+where
+    H: Fn(Request) -> Fut + Clone + Send + 'static,
+    Fut: std::future::Future<Output = Response> + Send,
+{
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(error) => {
+                warn!("Failed to accept connection: {error}");
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let Ok(Some(request)) = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await else {
+                debug!("Ignoring malformed request from {peer}");
+                return;
+            };
+            debug!("{} {} from {peer}", request.method, request.path);
+            let response = handler(request).await;
+            if let Err(error) = write_response(&mut stream, &response).await {
+                debug!("Failed to send response to {peer}: {error}");
+            }
+        });
+    }
+}
+
+/// Listen on the address. Returns the listener and the address clients can connect to, which
+/// has the actual port if port 0 was given and a loopback IP if an unspecified one was given.
+pub async fn bind(address: SocketAddr) -> Result<(TcpListener, SocketAddr)> {
+    let listen_failed = |source| ServeError::ListenFailed { address, source };
+    let listener = TcpListener::bind(address).await.map_err(listen_failed)?;
+    let mut local_address = listener.local_addr().map_err(listen_failed)?;
+    if local_address.ip().is_unspecified() {
+        local_address.set_ip(Ipv4Addr::LOCALHOST.into());
+    }
+    Ok((listener, local_address))
+}
+
+pub fn random_token() -> Result<String> {
+    let mut bytes = [0; 32];
+    getrandom::getrandom(&mut bytes).map_err(ServeError::TokenGenerationFailed)?;
+    Ok(BASE64_URL.encode(bytes))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: String,
+    expiration: String,
+    role_arn: String,
+}
+
+async fn container_credentials(provider: &CredentialsProvider, token: &str, request: Request) -> Response {
+    if request.method != "GET" {
+        return Response::error(405, "Only GET is supported");
+    }
+    if request.path != CREDENTIALS_PATH {
+        return Response::error(404, "Not found");
+    }
+    match request.headers.get("authorization") {
+        None => return Response::error(401, "Missing authorization token"),
+        Some(authorization) if authorization != token => {
+            return Response::error(403, "Invalid authorization token")
+        }
+        Some(_) => {}
+    }
+    match provider.credentials().await {
+        Ok(credentials) => Response::json(&ContainerCredentials {
+            access_key_id: credentials.access_key_id,
+            secret_access_key: credentials.secret_access_key,
+            token: credentials.session_token,
+            expiration: credentials.expiration.to_rfc3339_opts(SecondsFormat::Secs, true),
+            role_arn: provider.role_info().role_arn(),
+        }),
+        Err(error) => Response::error(500, &error),
+    }
+}
+
+pub fn print_env_vars(vars: &[(&str, String)], style: &EnvVarsStyle) {
+    for (name, value) in vars {
+        match style {
+            EnvVarsStyle::Sh => println!("export {name}={value}"),
+            EnvVarsStyle::PowerShell => println!("$env:{name}=\"{value}\""),
+        }
+    }
+}
+
+/// Serve the credentials with the container credentials provider protocol, until the process is
+/// stopped. Prints the environment variables that make SDKs and tools use it.
+pub async fn serve_container_credentials(role_info: RoleInfo, address: SocketAddr, style: &EnvVarsStyle) -> Result<()> {
+    let (listener, address) = bind(address).await?;
+    let token = Arc::new(random_token()?);
+    let provider = Arc::new(CredentialsProvider::new(role_info));
+
+    print_env_vars(
+        &[
+            ("AWS_CONTAINER_CREDENTIALS_FULL_URI", format!("http://{address}{CREDENTIALS_PATH}")),
+            ("AWS_CONTAINER_AUTHORIZATION_TOKEN", token.to_string()),
+            ("AWS_REGION", provider.role_info().region.clone()),
+            ("AWS_DEFAULT_REGION", provider.role_info().region.clone()),
+        ],
+        style,
+    );
+    info!("Serving credentials for {} on {address}", provider.role_info().role_arn());
+
+    tokio::spawn(provider.clone().keep_fresh());
+    serve_http(listener, move |request| {
+        let provider = provider.clone();
+        let token = token.clone();
+        async move { container_credentials(&provider, &token, request).await }
+    })
+    .await;
+    Ok(())
+}